@@ -0,0 +1,17 @@
+use std::process::Command;
+
+/// Exposes the current commit as `GIT_HASH` for /version. Builds outside a
+/// git checkout simply leave it unset.
+fn main() {
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+    let output = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success());
+    if let Some(output) = output {
+        let hash = String::from_utf8_lossy(&output.stdout);
+        println!("cargo:rustc-env=GIT_HASH={}", hash.trim());
+    }
+}
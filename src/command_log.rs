@@ -0,0 +1,58 @@
+use std::path::{Path, PathBuf};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncWriteExt, BufWriter},
+    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+};
+
+/// Once the log grows past this it is moved to `<path>.1`, replacing the
+/// previous one, and a fresh log is started.
+const MAX_LOG_SIZE: u64 = 10 * 1024 * 1024;
+
+/// Starts the task appending lines to the command log at `path`, returning
+/// the sender to queue lines on.
+pub fn spawn(path: PathBuf) -> UnboundedSender<String> {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        if let Err(err) = write_lines(&path, receiver).await {
+            log::error!("Command log {} stopped: {}", path.display(), err);
+        }
+    });
+    sender
+}
+
+async fn write_lines(path: &Path, mut receiver: UnboundedReceiver<String>) -> std::io::Result<()> {
+    let (mut writer, mut size) = open(path).await?;
+    while let Some(line) = receiver.recv().await {
+        size += write_line(&mut writer, &line).await?;
+        // Write out whatever else queued up meanwhile before flushing once.
+        while let Ok(line) = receiver.try_recv() {
+            size += write_line(&mut writer, &line).await?;
+        }
+        writer.flush().await?;
+
+        if size > MAX_LOG_SIZE {
+            let mut rotated = path.as_os_str().to_owned();
+            rotated.push(".1");
+            tokio::fs::rename(path, &rotated).await?;
+            (writer, size) = open(path).await?;
+        }
+    }
+    Ok(())
+}
+
+async fn open(path: &Path) -> std::io::Result<(BufWriter<File>, u64)> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    let size = file.metadata().await?.len();
+    Ok((BufWriter::new(file), size))
+}
+
+async fn write_line(writer: &mut BufWriter<File>, line: &str) -> std::io::Result<u64> {
+    writer.write_all(line.as_bytes()).await?;
+    writer.write_all(b"\n").await?;
+    Ok(line.len() as u64 + 1)
+}
@@ -0,0 +1,255 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::time::Duration;
+use teloxide::types::ChatId;
+
+const WEATHER_FORMAT: &str = "%l:+%c+%t+%p+%m";
+const GREETING_TEMPLATE: &str = "Good Morning!\n\nToday's weather in {weather}\n\n{todos}";
+/// Placeholders GREETING_TEMPLATE may use.
+const GREETING_PLACEHOLDERS: &[&str] = &["weather", "name", "todos"];
+
+/// Settings read from the environment at startup. Every value has a default
+/// matching the bot's original hardcoded behaviour.
+pub struct Config {
+    pub data_dir: PathBuf,
+    pub owner_name: String,
+    pub admin_chat_id: Option<ChatId>,
+    /// Only these chats may use the bot, everyone may when unset.
+    pub allowed_chat_ids: Option<HashSet<ChatId>>,
+    pub default_city: String,
+    pub default_country: String,
+    pub greeting_hour: u32,
+    pub greeting_minute: u32,
+    pub greeting_template: String,
+    pub rate_limit: Duration,
+    pub log_commands: bool,
+    pub coin_heads_chance: f64,
+    pub http_timeout: Duration,
+    pub cat_api_url: String,
+    pub cat_breeds_url: String,
+    pub dog_api_url: String,
+    pub dictionary_api_url: String,
+    pub useless_facts_url: String,
+    pub weather_api_url: String,
+    pub joke_api_url: String,
+    pub quote_api_url: String,
+    pub translate_url: String,
+    pub translate_api_key: Option<String>,
+    pub crypto_api_url: String,
+    pub news_api_url: String,
+    pub news_api_key: Option<String>,
+    pub holidays_api_url: String,
+    pub summary_api_url: String,
+    pub summary_api_key: Option<String>,
+    pub summary_model: String,
+    pub summary_timeout: Duration,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        Config {
+            data_dir: PathBuf::from(env_or("DATA_DIR", ".")),
+            owner_name: env_or("OWNER_NAME", "Herr Das"),
+            admin_chat_id: std::env::var("ADMIN_CHAT_ID")
+                .ok()
+                .and_then(|value| value.trim().parse::<i64>().ok())
+                .map(ChatId),
+            allowed_chat_ids: std::env::var("ALLOWED_CHAT_IDS")
+                .ok()
+                .map(|value| parse_chat_ids(&value)),
+            default_city: env_or("DEFAULT_CITY", "Hyderabad"),
+            default_country: env_or("DEFAULT_COUNTRY", "IN"),
+            greeting_hour: parse_env("GREETING_HOUR", 8, |hour| hour <= 23),
+            greeting_minute: parse_env("GREETING_MINUTE", 0, |minute| minute <= 59),
+            greeting_template: greeting_template(),
+            rate_limit: Duration::from_secs(parse_env("RATE_LIMIT_SECONDS", 2, |_| true)),
+            log_commands: env_flag("LOG_COMMANDS", false),
+            coin_heads_chance: parse_env("COIN_HEADS_CHANCE", 0.5, |chance| {
+                (0.0..=1.0).contains(&chance)
+            }),
+            http_timeout: Duration::from_secs(parse_env("HTTP_TIMEOUT_SECONDS", 10, |secs| {
+                secs > 0
+            })),
+            cat_api_url: env_or("CAT_API_URL", "https://api.thecatapi.com/v1/images/search"),
+            cat_breeds_url: env_or("CAT_BREEDS_URL", "https://api.thecatapi.com/v1/breeds"),
+            dog_api_url: env_or("DOG_API_URL", "https://dog.ceo/api/breeds/image/random"),
+            dictionary_api_url: env_or(
+                "DICTIONARY_API_URL",
+                "https://api.dictionaryapi.dev/api/v2/entries",
+            ),
+            useless_facts_url: env_or(
+                "USELESS_FACTS_URL",
+                "https://uselessfacts.jsph.pl/random.json",
+            ),
+            weather_api_url: env_or("WEATHER_API_URL", "https://wttr.in"),
+            joke_api_url: env_or(
+                "JOKE_API_URL",
+                "https://official-joke-api.appspot.com/random_joke",
+            ),
+            quote_api_url: env_or("QUOTE_API_URL", "https://api.quotable.io/random"),
+            translate_url: env_or("TRANSLATE_URL", "https://libretranslate.com/translate"),
+            translate_api_key: std::env::var("TRANSLATE_API_KEY")
+                .ok()
+                .filter(|key| !key.trim().is_empty()),
+            crypto_api_url: env_or(
+                "CRYPTO_API_URL",
+                "https://api.coingecko.com/api/v3/simple/price",
+            ),
+            news_api_url: env_or("NEWS_API_URL", "https://newsapi.org/v2/top-headlines"),
+            news_api_key: std::env::var("NEWS_API_KEY")
+                .ok()
+                .filter(|key| !key.trim().is_empty()),
+            holidays_api_url: env_or(
+                "HOLIDAYS_API_URL",
+                "https://date.nager.at/api/v3/NextPublicHolidays",
+            ),
+            summary_api_url: env_or(
+                "SUMMARY_API_URL",
+                "https://api.openai.com/v1/chat/completions",
+            ),
+            summary_api_key: std::env::var("SUMMARY_API_KEY")
+                .ok()
+                .filter(|key| !key.trim().is_empty()),
+            summary_model: env_or("SUMMARY_MODEL", "gpt-4o-mini"),
+            summary_timeout: Duration::from_secs(parse_env(
+                "SUMMARY_TIMEOUT_SECONDS",
+                60,
+                |secs| secs > 0,
+            )),
+        }
+    }
+
+    /// Whether `chat_id` passes the ALLOWED_CHAT_IDS allowlist.
+    pub fn is_allowed(&self, chat_id: ChatId) -> bool {
+        self.allowed_chat_ids
+            .as_ref()
+            .is_none_or(|allowed| allowed.contains(&chat_id))
+    }
+
+    /// Where the persistence file `name` lives.
+    pub fn data_path(&self, name: &str) -> PathBuf {
+        self.data_dir.join(name)
+    }
+
+    /// wttr.in url for a PNG weather report of `city`.
+    pub fn weather_png_url(&self, city: &str) -> String {
+        format!("{}/{}.png", self.weather_api_url, urlencoding::encode(city))
+    }
+
+    /// wttr.in url for the one line weather report of `city`, in the compact
+    /// default format unless a /setweatherformat `format` is given.
+    pub fn weather_url(&self, city: &str, format: Option<&str>) -> String {
+        format!(
+            "{}/{}?format={}",
+            self.weather_api_url,
+            urlencoding::encode(city),
+            format.unwrap_or(WEATHER_FORMAT)
+        )
+    }
+}
+
+fn env_or(name: &str, default: &str) -> String {
+    std::env::var(name).unwrap_or_else(|_| default.to_string())
+}
+
+/// Reads GREETING_TEMPLATE, falling back to the default when it uses an
+/// unknown placeholder.
+fn greeting_template() -> String {
+    let Ok(template) = std::env::var("GREETING_TEMPLATE") else {
+        return GREETING_TEMPLATE.to_string();
+    };
+    let mut rest = template.as_str();
+    while let Some(start) = rest.find('{') {
+        let Some(len) = rest[start..].find('}') else {
+            break;
+        };
+        let placeholder = &rest[start + 1..start + len];
+        if !GREETING_PLACEHOLDERS.contains(&placeholder) {
+            log::warn!(
+                "Unknown placeholder {{{}}} in GREETING_TEMPLATE, using the default greeting",
+                placeholder
+            );
+            return GREETING_TEMPLATE.to_string();
+        }
+        rest = &rest[start + len + 1..];
+    }
+    template
+}
+
+/// Parses a comma separated list of chat ids, warning about and skipping
+/// entries that aren't numbers.
+fn parse_chat_ids(value: &str) -> HashSet<ChatId> {
+    let mut ids = HashSet::new();
+    for entry in value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+    {
+        match entry.parse::<i64>() {
+            Ok(id) => {
+                ids.insert(ChatId(id));
+            }
+            Err(_) => log::warn!("Ignoring invalid chat id '{}' in ALLOWED_CHAT_IDS", entry),
+        }
+    }
+    if ids.is_empty() {
+        log::warn!("ALLOWED_CHAT_IDS has no valid chat ids, nobody can use the bot");
+    } else {
+        log::info!("Only {} allowed chats can use the bot", ids.len());
+    }
+    ids
+}
+
+/// Reads an on/off flag from the environment, accepting `1`/`0`, `true`/`false`,
+/// `yes`/`no` and `on`/`off` in any case.
+fn env_flag(name: &str, default: bool) -> bool {
+    match std::env::var(name) {
+        Ok(value) => parse_flag(&value).unwrap_or_else(|| {
+            log::warn!("Invalid {} '{}', using {}", name, value, default);
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
+fn parse_flag(value: &str) -> Option<bool> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Some(true),
+        "0" | "false" | "no" | "off" => Some(false),
+        _ => None,
+    }
+}
+
+/// Parses `name` from the environment, warning and falling back to `default`
+/// when it is set but unparsable or rejected by `valid`.
+fn parse_env<T>(name: &str, default: T, valid: impl Fn(T) -> bool) -> T
+where
+    T: std::str::FromStr + Copy + std::fmt::Display,
+{
+    match std::env::var(name) {
+        Ok(value) => match value.trim().parse::<T>() {
+            Ok(parsed) if valid(parsed) => parsed,
+            _ => {
+                log::warn!("Invalid {} '{}', using {}", name, value, default);
+                default
+            }
+        },
+        Err(_) => default,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_accept_common_spellings() {
+        for value in ["1", "true", "YES", "On", " on "] {
+            assert_eq!(parse_flag(value), Some(true), "{}", value);
+        }
+        for value in ["0", "False", "no", "OFF"] {
+            assert_eq!(parse_flag(value), Some(false), "{}", value);
+        }
+        assert_eq!(parse_flag("maybe"), None);
+    }
+}
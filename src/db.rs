@@ -0,0 +1,359 @@
+use crate::{Alarm, Countdown, TodoItem, TodoKey};
+use rusqlite::{params, Connection, Result};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use teloxide::types::{ChatId, UserId};
+
+/// Schema changes, applied in order. The database's `user_version` is the
+/// number of migrations already applied, so only append to this list.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE IF NOT EXISTS users (
+        chat_id INTEGER PRIMARY KEY,
+        subscribed INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS todos (
+        chat_id INTEGER NOT NULL,
+        position INTEGER NOT NULL,
+        text TEXT NOT NULL,
+        done INTEGER NOT NULL,
+        created TEXT NOT NULL,
+        PRIMARY KEY (chat_id, position)
+    );",
+    "ALTER TABLE todos ADD COLUMN completed TEXT;",
+    "ALTER TABLE todos ADD COLUMN due TEXT;",
+    // user_id is 0 for lists shared by the whole chat.
+    "CREATE TABLE todos_by_user (
+        chat_id INTEGER NOT NULL,
+        user_id INTEGER NOT NULL,
+        position INTEGER NOT NULL,
+        text TEXT NOT NULL,
+        done INTEGER NOT NULL,
+        created TEXT NOT NULL,
+        completed TEXT,
+        due TEXT,
+        PRIMARY KEY (chat_id, user_id, position)
+    );
+    INSERT INTO todos_by_user
+        SELECT chat_id, 0, position, text, done, created, completed, due FROM todos;
+    DROP TABLE todos;
+    ALTER TABLE todos_by_user RENAME TO todos;",
+    "ALTER TABLE todos ADD COLUMN recurring INTEGER NOT NULL DEFAULT 0;",
+    "CREATE TABLE alarms (
+        chat_id INTEGER NOT NULL,
+        position INTEGER NOT NULL,
+        time TEXT NOT NULL,
+        text TEXT NOT NULL,
+        PRIMARY KEY (chat_id, position)
+    );",
+    "CREATE TABLE countdowns (
+        chat_id INTEGER NOT NULL,
+        position INTEGER NOT NULL,
+        date TEXT NOT NULL,
+        name TEXT NOT NULL,
+        PRIMARY KEY (chat_id, position)
+    );",
+    "CREATE TABLE nicknames (
+        chat_id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL
+    );",
+    "CREATE TABLE languages (
+        chat_id INTEGER PRIMARY KEY,
+        language TEXT NOT NULL
+    );",
+    "CREATE TABLE weather_formats (
+        chat_id INTEGER PRIMARY KEY,
+        format TEXT NOT NULL
+    );",
+    "CREATE TABLE personal_todo_chats (chat_id INTEGER PRIMARY KEY);",
+];
+
+/// Opens the database and brings its schema up to date. Also returns whether
+/// the database was just created, in which case the legacy
+/// `todo.json`/`users.txt` files still need importing.
+pub fn open(path: &Path) -> Result<(Connection, bool)> {
+    let mut conn = Connection::open(path)?;
+    let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    let tx = conn.transaction()?;
+    for migration in MIGRATIONS.iter().skip(version as usize) {
+        tx.execute_batch(migration)?;
+    }
+    tx.pragma_update(None, "user_version", MIGRATIONS.len() as i64)?;
+    tx.commit()?;
+    Ok((conn, version == 0))
+}
+
+pub fn import(
+    conn: &mut Connection,
+    users: &HashSet<ChatId>,
+    unsubscribed: &HashSet<ChatId>,
+    todos: &HashMap<ChatId, Vec<TodoItem>>,
+) -> Result<()> {
+    let tx = conn.transaction()?;
+    for user in users {
+        set_user(&tx, *user, true)?;
+    }
+    for user in unsubscribed {
+        set_user(&tx, *user, false)?;
+    }
+    for (chat_id, items) in todos {
+        insert_todos(&tx, TodoKey::shared(*chat_id), items)?;
+    }
+    tx.commit()
+}
+
+/// Returns the subscribed and unsubscribed users.
+pub fn load_users(conn: &Connection) -> Result<(HashSet<ChatId>, HashSet<ChatId>)> {
+    let mut statement = conn.prepare("SELECT chat_id, subscribed FROM users")?;
+    let rows = statement.query_map([], |row| Ok((ChatId(row.get(0)?), row.get::<_, bool>(1)?)))?;
+    let (mut subscribed, mut unsubscribed) = (HashSet::new(), HashSet::new());
+    for row in rows {
+        match row? {
+            (chat_id, true) => subscribed.insert(chat_id),
+            (chat_id, false) => unsubscribed.insert(chat_id),
+        };
+    }
+    Ok((subscribed, unsubscribed))
+}
+
+pub fn set_user(conn: &Connection, chat_id: ChatId, subscribed: bool) -> Result<()> {
+    conn.execute(
+        "INSERT INTO users (chat_id, subscribed) VALUES (?1, ?2)
+         ON CONFLICT (chat_id) DO UPDATE SET subscribed = excluded.subscribed",
+        params![chat_id.0, subscribed],
+    )?;
+    Ok(())
+}
+
+pub fn remove_user(conn: &Connection, chat_id: ChatId) -> Result<()> {
+    conn.execute("DELETE FROM users WHERE chat_id = ?1", params![chat_id.0])?;
+    Ok(())
+}
+
+pub fn load_todos(conn: &Connection) -> Result<HashMap<TodoKey, Vec<TodoItem>>> {
+    let mut statement = conn.prepare(
+        "SELECT chat_id, user_id, text, done, created, completed, due, recurring FROM todos
+         ORDER BY chat_id, user_id, position",
+    )?;
+    let rows = statement.query_map([], |row| {
+        let user = row.get::<_, i64>(1)?;
+        Ok((
+            TodoKey {
+                chat: ChatId(row.get(0)?),
+                user: (user != 0).then_some(UserId(user as u64)),
+            },
+            TodoItem {
+                text: row.get(2)?,
+                done: row.get(3)?,
+                created: row.get(4)?,
+                completed: row.get(5)?,
+                due: row.get(6)?,
+                recurring: row.get(7)?,
+            },
+        ))
+    })?;
+    let mut todos: HashMap<TodoKey, Vec<TodoItem>> = HashMap::new();
+    for row in rows {
+        let (key, item) = row?;
+        todos.entry(key).or_default().push(item);
+    }
+    Ok(todos)
+}
+
+/// Replaces the stored todos of one list, keeping them in the given order.
+pub fn save_todos(conn: &mut Connection, key: TodoKey, items: &[TodoItem]) -> Result<()> {
+    let tx = conn.transaction()?;
+    tx.execute(
+        "DELETE FROM todos WHERE chat_id = ?1 AND user_id = ?2",
+        params![key.chat.0, user_column(key)],
+    )?;
+    insert_todos(&tx, key, items)?;
+    tx.commit()
+}
+
+pub fn load_alarms(conn: &Connection) -> Result<HashMap<ChatId, Vec<Alarm>>> {
+    let mut statement =
+        conn.prepare("SELECT chat_id, time, text FROM alarms ORDER BY chat_id, position")?;
+    let rows = statement.query_map([], |row| {
+        let alarm = Alarm {
+            time: row.get(1)?,
+            text: row.get(2)?,
+        };
+        Ok((ChatId(row.get(0)?), alarm))
+    })?;
+    let mut alarms: HashMap<ChatId, Vec<Alarm>> = HashMap::new();
+    for row in rows {
+        let (chat_id, alarm) = row?;
+        alarms.entry(chat_id).or_default().push(alarm);
+    }
+    Ok(alarms)
+}
+
+/// Replaces the stored alarms of one chat, keeping them in the given order.
+pub fn save_alarms(conn: &mut Connection, chat_id: ChatId, alarms: &[Alarm]) -> Result<()> {
+    let tx = conn.transaction()?;
+    tx.execute("DELETE FROM alarms WHERE chat_id = ?1", params![chat_id.0])?;
+    {
+        let mut statement = tx.prepare(
+            "INSERT INTO alarms (chat_id, position, time, text) VALUES (?1, ?2, ?3, ?4)",
+        )?;
+        for (position, alarm) in alarms.iter().enumerate() {
+            statement.execute(params![chat_id.0, position as i64, alarm.time, alarm.text])?;
+        }
+    }
+    tx.commit()
+}
+
+pub fn load_countdowns(conn: &Connection) -> Result<HashMap<ChatId, Vec<Countdown>>> {
+    let mut statement =
+        conn.prepare("SELECT chat_id, date, name FROM countdowns ORDER BY chat_id, position")?;
+    let rows = statement.query_map([], |row| {
+        let countdown = Countdown {
+            date: row.get(1)?,
+            name: row.get(2)?,
+        };
+        Ok((ChatId(row.get(0)?), countdown))
+    })?;
+    let mut countdowns: HashMap<ChatId, Vec<Countdown>> = HashMap::new();
+    for row in rows {
+        let (chat_id, countdown) = row?;
+        countdowns.entry(chat_id).or_default().push(countdown);
+    }
+    Ok(countdowns)
+}
+
+/// Replaces the stored countdowns of one chat, keeping them in the given order.
+pub fn save_countdowns(
+    conn: &mut Connection,
+    chat_id: ChatId,
+    countdowns: &[Countdown],
+) -> Result<()> {
+    let tx = conn.transaction()?;
+    tx.execute(
+        "DELETE FROM countdowns WHERE chat_id = ?1",
+        params![chat_id.0],
+    )?;
+    {
+        let mut statement = tx.prepare(
+            "INSERT INTO countdowns (chat_id, position, date, name) VALUES (?1, ?2, ?3, ?4)",
+        )?;
+        for (position, countdown) in countdowns.iter().enumerate() {
+            statement.execute(params![
+                chat_id.0,
+                position as i64,
+                countdown.date,
+                countdown.name
+            ])?;
+        }
+    }
+    tx.commit()
+}
+
+/// Returns the chats that switched to /mode personal.
+pub fn load_personal_todo_chats(conn: &Connection) -> Result<HashSet<ChatId>> {
+    let mut statement = conn.prepare("SELECT chat_id FROM personal_todo_chats")?;
+    let rows = statement.query_map([], |row| Ok(ChatId(row.get(0)?)))?;
+    rows.collect()
+}
+
+pub fn set_personal_todos(conn: &Connection, chat_id: ChatId, personal: bool) -> Result<()> {
+    if personal {
+        conn.execute(
+            "INSERT OR IGNORE INTO personal_todo_chats (chat_id) VALUES (?1)",
+            params![chat_id.0],
+        )?;
+    } else {
+        conn.execute(
+            "DELETE FROM personal_todo_chats WHERE chat_id = ?1",
+            params![chat_id.0],
+        )?;
+    }
+    Ok(())
+}
+
+pub fn load_nicknames(conn: &Connection) -> Result<HashMap<ChatId, String>> {
+    load_chat_texts(conn, "nicknames", "name")
+}
+
+pub fn set_nickname(conn: &Connection, chat_id: ChatId, name: &str) -> Result<()> {
+    set_chat_text(conn, "nicknames", "name", chat_id, Some(name))
+}
+
+pub fn load_languages(conn: &Connection) -> Result<HashMap<ChatId, String>> {
+    load_chat_texts(conn, "languages", "language")
+}
+
+/// Sets the preferred language of a chat, or resets it for `None`.
+pub fn set_language(conn: &Connection, chat_id: ChatId, language: Option<&str>) -> Result<()> {
+    set_chat_text(conn, "languages", "language", chat_id, language)
+}
+
+pub fn load_weather_formats(conn: &Connection) -> Result<HashMap<ChatId, String>> {
+    load_chat_texts(conn, "weather_formats", "format")
+}
+
+/// Sets the wttr.in format of a chat, or resets it for `None`.
+pub fn set_weather_format(conn: &Connection, chat_id: ChatId, format: Option<&str>) -> Result<()> {
+    set_chat_text(conn, "weather_formats", "format", chat_id, format)
+}
+
+/// Reads a table holding one text column per chat.
+fn load_chat_texts(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+) -> Result<HashMap<ChatId, String>> {
+    let mut statement = conn.prepare(&format!("SELECT chat_id, {column} FROM {table}"))?;
+    let rows = statement.query_map([], |row| Ok((ChatId(row.get(0)?), row.get(1)?)))?;
+    rows.collect()
+}
+
+/// Sets the text of a chat in a table read by `load_chat_texts`, or deletes
+/// its row for `None`.
+fn set_chat_text(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    chat_id: ChatId,
+    text: Option<&str>,
+) -> Result<()> {
+    match text {
+        Some(text) => conn.execute(
+            &format!(
+                "INSERT INTO {table} (chat_id, {column}) VALUES (?1, ?2)
+                 ON CONFLICT (chat_id) DO UPDATE SET {column} = excluded.{column}"
+            ),
+            params![chat_id.0, text],
+        )?,
+        None => conn.execute(
+            &format!("DELETE FROM {table} WHERE chat_id = ?1"),
+            params![chat_id.0],
+        )?,
+    };
+    Ok(())
+}
+
+fn user_column(key: TodoKey) -> i64 {
+    key.user.map_or(0, |user| user.0 as i64)
+}
+
+fn insert_todos(conn: &Connection, key: TodoKey, items: &[TodoItem]) -> Result<()> {
+    let mut statement = conn.prepare(
+        "INSERT INTO todos
+             (chat_id, user_id, position, text, done, created, completed, due, recurring)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+    )?;
+    for (position, item) in items.iter().enumerate() {
+        statement.execute(params![
+            key.chat.0,
+            user_column(key),
+            position as i64,
+            item.text,
+            item.done,
+            item.created,
+            item.completed,
+            item.due,
+            item.recurring
+        ])?;
+    }
+    Ok(())
+}
@@ -1,22 +1,192 @@
-use chrono::{Local, Timelike};
+mod command_log;
+mod config;
+mod db;
+
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Timelike, Utc};
+use chrono_english::{parse_date_string, Dialect};
+use chrono_tz::Tz;
+use config::Config;
 use dotenv::dotenv;
-use lazy_static::lazy_static;
-use reqwest::{Error, Url};
+use futures::StreamExt;
+use rand::RngExt;
+use reqwest::Url;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::{from_str, to_string, Value};
 use std::{
-    collections::{HashMap, HashSet},
-    time::Duration,
+    collections::{HashMap, HashSet, VecDeque},
+    io,
+    ops::RangeInclusive,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 use teloxide::{
+    net::Download,
     prelude::*,
-    types::{InputFile, ParseMode},
-    utils::command::BotCommands,
+    requests::Output,
+    types::{
+        ChatAction, InlineKeyboardButton, InlineKeyboardMarkup, InputFile, MessageId, ParseMode,
+        UserId,
+    },
+    utils::{command::BotCommands, html::escape},
+    ApiError, RequestError,
+};
+use tokio::{
+    sync::{mpsc::UnboundedSender, Mutex},
+    task::AbortHandle,
+    time::sleep,
 };
-use tokio::{sync::Mutex, time::sleep};
 
-lazy_static! {
-    static ref TODO_LIST: Mutex<HashMap<ChatId, Vec<String>>> = Mutex::new(HashMap::new());
-    static ref USERS_LIST: Mutex<HashSet<ChatId>> = Mutex::new(HashSet::new());
+/// Everything the handlers share. Created once in `main` and injected into
+/// `answer` by the dispatcher.
+struct State {
+    todo_list: Mutex<HashMap<TodoKey, Vec<TodoItem>>>,
+    personal_todo_chats: Mutex<HashSet<ChatId>>,
+    users_list: Mutex<HashSet<ChatId>>,
+    city_list: Mutex<HashMap<ChatId, String>>,
+    weather_formats: Mutex<HashMap<ChatId, String>>,
+    timezones: Mutex<HashMap<ChatId, Tz>>,
+    nicknames: Mutex<HashMap<ChatId, String>>,
+    languages: Mutex<HashMap<ChatId, String>>,
+    unsubscribed_list: Mutex<HashSet<ChatId>>,
+    last_command: Mutex<HashMap<ChatId, Instant>>,
+    last_feedback: Mutex<HashMap<ChatId, Instant>>,
+    command_counts: Mutex<HashMap<String, u64>>,
+    chat_activity: Mutex<HashMap<ChatId, ChatActivity>>,
+    last_undo: Mutex<HashMap<TodoKey, UndoAction>>,
+    reminders: Mutex<HashMap<ChatId, Vec<Reminder>>>,
+    alarms: Mutex<HashMap<ChatId, Vec<Alarm>>>,
+    countdowns: Mutex<HashMap<ChatId, Vec<Countdown>>>,
+    /// Cat API breed ids by their lowercase name and id, for /cat <breed>.
+    cat_breeds: Mutex<HashMap<String, String>>,
+    /// The last RECENT_CATS images each chat got from /cat.
+    recent_cats: Mutex<HashMap<ChatId, VecDeque<Url>>>,
+    last_fired_reminder: Mutex<HashMap<ChatId, String>>,
+    next_reminder_id: AtomicU64,
+    db: std::sync::Mutex<rusqlite::Connection>,
+    http: reqwest::Client,
+    command_log: Option<UnboundedSender<String>>,
+    start_time: Instant,
+}
+
+impl State {
+    fn new(
+        db: rusqlite::Connection,
+        http: reqwest::Client,
+        command_log: Option<UnboundedSender<String>>,
+    ) -> Self {
+        State {
+            todo_list: Mutex::new(HashMap::new()),
+            personal_todo_chats: Mutex::new(HashSet::new()),
+            users_list: Mutex::new(HashSet::new()),
+            city_list: Mutex::new(HashMap::new()),
+            weather_formats: Mutex::new(HashMap::new()),
+            timezones: Mutex::new(HashMap::new()),
+            nicknames: Mutex::new(HashMap::new()),
+            languages: Mutex::new(HashMap::new()),
+            unsubscribed_list: Mutex::new(HashSet::new()),
+            last_command: Mutex::new(HashMap::new()),
+            last_feedback: Mutex::new(HashMap::new()),
+            command_counts: Mutex::new(HashMap::new()),
+            chat_activity: Mutex::new(HashMap::new()),
+            last_undo: Mutex::new(HashMap::new()),
+            reminders: Mutex::new(HashMap::new()),
+            alarms: Mutex::new(HashMap::new()),
+            countdowns: Mutex::new(HashMap::new()),
+            cat_breeds: Mutex::new(HashMap::new()),
+            recent_cats: Mutex::new(HashMap::new()),
+            last_fired_reminder: Mutex::new(HashMap::new()),
+            next_reminder_id: AtomicU64::new(0),
+            db: std::sync::Mutex::new(db),
+            http,
+            command_log,
+            start_time: Instant::now(),
+        }
+    }
+}
+
+/// The most recent destructive todo change of a chat, as restored by /undo.
+enum UndoAction {
+    Completed(TodoItem),
+    Cleared(Vec<TodoItem>),
+}
+
+/// A pending /remind, kept until it fires.
+struct Reminder {
+    id: u64,
+    fire_at: DateTime<Utc>,
+    text: String,
+    task: AbortHandle,
+}
+
+/// A daily /alarm, firing at `time` in the user's timezone.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Alarm {
+    time: NaiveTime,
+    text: String,
+}
+
+/// How much a chat uses the bot, for the admin's /stats.
+#[derive(Default, Serialize, Deserialize)]
+struct ChatActivity {
+    /// Label of whoever last sent a command, see `sender_label`.
+    name: Option<String>,
+    commands: u64,
+}
+
+/// A named event from /countdown.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Countdown {
+    date: NaiveDate,
+    name: String,
+}
+
+const DB_PATH: &str = "herr-jr.db";
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Identifies a todo list: the chat's shared one, or a member's own list in
+/// a group that switched to /mode personal.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct TodoKey {
+    chat: ChatId,
+    user: Option<UserId>,
+}
+
+impl TodoKey {
+    fn shared(chat: ChatId) -> Self {
+        TodoKey { chat, user: None }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct TodoItem {
+    text: String,
+    done: bool,
+    created: DateTime<Local>,
+    #[serde(default)]
+    completed: Option<DateTime<Local>>,
+    #[serde(default)]
+    due: Option<NaiveDate>,
+    /// Comes back undone every day, see `reset_recurring`.
+    #[serde(default)]
+    recurring: bool,
+}
+
+impl TodoItem {
+    fn new(text: String) -> Self {
+        TodoItem {
+            text,
+            done: false,
+            created: Local::now(),
+            completed: None,
+            due: None,
+            recurring: false,
+        }
+    }
 }
 
 #[tokio::main]
@@ -42,224 +212,4198 @@ async fn main() {
         }
     };
 
-    log::info!("Reading todo.txt...");
-    match std::fs::read_to_string("todo.json") {
-        Ok(content) => {
-            let tasks: HashMap<ChatId, Vec<String>> = from_str(&content).unwrap();
-            *TODO_LIST.lock().await = tasks;
-        }
-        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
-            log::info!("todo.json not found");
-        }
-        Err(err) => {
-            log::error!("Failed to read todo.json: {}", err);
-        }
+    let config = Arc::new(Config::from_env());
+    std::fs::create_dir_all(&config.data_dir).expect("Unable to create the data directory");
+
+    let db_path = config.data_path(DB_PATH);
+    let (conn, fresh_db) = db::open(&db_path).expect("Unable to open database");
+    let http = reqwest::Client::builder()
+        .connect_timeout(config.http_timeout)
+        .timeout(config.http_timeout)
+        .build()
+        .expect("Unable to build the HTTP client");
+    let command_log = config
+        .log_commands
+        .then(|| command_log::spawn(config.data_path("commands.log")));
+    let state = Arc::new(State::new(conn, http, command_log));
+
+    log::info!("Loading state from {}...", db_path.display());
+    if let Err(err) = load_state(&config, &state, fresh_db).await {
+        log::error!("Failed to load state from {}: {}", db_path.display(), err);
     }
 
-    log::info!("Reading users.txt...");
-    match std::fs::read_to_string("users.txt") {
-        Ok(content) => {
-            let users: Vec<ChatId> = content
-                .lines()
-                .map(|line| ChatId(line.parse::<i64>().unwrap()))
-                .collect();
-            *USERS_LIST.lock().await = users.into_iter().collect();
-        }
-        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
-            log::info!("users.txt not found");
-        }
-        Err(err) => {
-            log::error!("Failed to read users.txt: {}", err);
+    log::info!("Reading cities.json...");
+    *state.city_list.lock().await = read_json(&config.data_path("cities.json"));
+
+    log::info!("Reading timezones.json...");
+    *state.timezones.lock().await = read_json(&config.data_path("timezones.json"));
+
+    log::info!("Reading metrics.json...");
+    *state.command_counts.lock().await = read_json(&config.data_path("metrics.json"));
+
+    log::info!("Reading activity.json...");
+    *state.chat_activity.lock().await = read_json(&config.data_path("activity.json"));
+
+    let breeds_config = config.clone();
+    let breeds_state = state.clone();
+    tokio::spawn(async move {
+        match get_cat_breeds(&breeds_state.http, &breeds_config).await {
+            Ok(breeds) => {
+                log::info!("Loaded the cat breeds");
+                *breeds_state.cat_breeds.lock().await = breeds;
+            }
+            Err(err) => log::warn!(
+                "Failed to load cat breeds, /cat <breed> is disabled: {}",
+                err
+            ),
         }
-    }
+    });
 
     send_to_all(
         &bot,
+        &state,
         "Bot started successfully. Use /help to see available commands.",
     )
     .await;
 
-    // log::info!("Sending greeting messages...");
-    // for user in USERS_LIST.lock().await.iter() {
-    //     let resp = reqwest::get("https://wttr.in/Hyderabad?format=%l:+%c+%t+%p+%m").await.unwrap();
-    //     let content = resp.text().await.unwrap();
-    //     bot.send_message(user.clone(), format!("Hi!\n\nToday's weather in {}\n\nYour todo list is: \n-{}", content, TODO_LIST.lock().await.join("\n-"))).await.unwrap();
-    // }
-
     let bot_copy = bot.clone();
+    let greeting_config = config.clone();
+    let greeting_state = state.clone();
 
     tokio::spawn(async move {
+        let (config, state) = (greeting_config, greeting_state);
+        log::info!(
+            "Greeting scheduled daily at {:02}:{:02} in each user's timezone",
+            config.greeting_hour,
+            config.greeting_minute
+        );
+
+        // Wake up at the start of every minute, bring back yesterday's daily
+        // todos and greet whoever reached the greeting time in their own
+        // timezone, then ring their alarms. Greeting whoever is past the
+        // greeting time, rather than at it, catches up after a late tick.
+        let greeting_time =
+            NaiveTime::from_hms_opt(config.greeting_hour, config.greeting_minute, 0)
+                .expect("Config only accepts valid greeting times");
+        // Users already past it at startup are next greeted tomorrow, not on
+        // every restart.
+        let mut last_greeted: HashMap<ChatId, NaiveDate> = HashMap::new();
+        let users: Vec<ChatId> = state.users_list.lock().await.iter().copied().collect();
+        let timezones = state.timezones.lock().await.clone();
+        for user in users {
+            let now = user_now(timezones.get(&user).copied());
+            if now.time() >= greeting_time {
+                last_greeted.insert(user, now.date());
+            }
+        }
+        // Minutes since the epoch whose alarms have rung.
+        let mut last_alarm_minute = Utc::now().timestamp() / 60;
         loop {
-            let now = Local::now();
-            let next_time = (now.date() + chrono::Duration::days(1)).and_hms(8, 0, 0); // Next day at 8 AM
-            let duration_until_next_time = (next_time - now)
-                .to_std()
-                .unwrap_or_else(|_| std::time::Duration::from_secs(0));
-
-            sleep(duration_until_next_time).await;
-            log::info!("Sending greeting messages...");
-            let resp = reqwest::get("https://wttr.in/Hyderabad?format=%l:+%c+%t+%p+%m")
-                .await
-                .unwrap();
-            let content = resp.text().await.unwrap();
-            send_to_all(
-                &bot,
-                format!("Good Morning!\n\nToday's weather in {}", content,).as_str(),
-            )
-            .await;
+            let second = u64::from(Local::now().second());
+            sleep(Duration::from_secs(60 - second.min(59))).await;
+
+            let users: Vec<ChatId> = state.users_list.lock().await.iter().copied().collect();
+            let timezones = state.timezones.lock().await.clone();
+            let mut due = Vec::new();
+            for user in users {
+                let now = user_now(timezones.get(&user).copied());
+                if now.time() >= greeting_time && last_greeted.get(&user) != Some(&now.date()) {
+                    last_greeted.insert(user, now.date());
+                    due.push(user);
+                }
+            }
+
+            let mut reset = Vec::new();
+            for (key, tasks) in state.todo_list.lock().await.iter_mut() {
+                let timezone = timezones.get(&key.chat).copied();
+                if reset_recurring(tasks, timezone, user_now(timezone).date()) {
+                    reset.push(*key);
+                }
+            }
+            for key in reset {
+                log::info!("Reset the daily todos of {:?}", key);
+                save_todos(&state, key).await;
+            }
+
+            if !due.is_empty() {
+                log::info!("Sending greeting messages to {} users...", due.len());
+                let (bot, config, state) = (bot.clone(), config.clone(), state.clone());
+                tokio::spawn(async move { send_greetings(&bot, &config, &state, &due).await });
+            }
+
+            // Ring the alarms of every minute since the last tick, so none
+            // are skipped when a tick runs late.
+            let minute = Utc::now().timestamp() / 60;
+            let ringing = due_alarms(
+                &*state.alarms.lock().await,
+                &timezones,
+                last_alarm_minute + 1..=minute,
+            );
+            last_alarm_minute = last_alarm_minute.max(minute);
+            if !ringing.is_empty() {
+                let bot = bot.clone();
+                tokio::spawn(async move {
+                    for (chat_id, text) in ringing {
+                        log::info!("Ringing alarm '{}' of {}", text, chat_id);
+                        let alarm = bot.send_message(chat_id, format!("⏰ {}", text));
+                        if let Err(err) = send_retrying(alarm).await {
+                            log::error!("Failed to ring alarm of {}: {}", chat_id, err);
+                        }
+                    }
+                });
+            }
+        }
+    });
+
+    let handler = dptree::entry()
+        .branch(
+            Update::filter_message()
+                .filter_command::<Command>()
+                .endpoint(answer),
+        )
+        .branch(
+            Update::filter_message()
+                .filter(|msg: Message| msg.document().is_some_and(is_todo_export))
+                .endpoint(import_todos),
+        )
+        .branch(Update::filter_callback_query().endpoint(handle_callback));
+    let mut dispatcher = Dispatcher::builder(bot_copy.clone(), handler)
+        .dependencies(dptree::deps![config.clone(), state.clone()])
+        .default_handler(|_| async {})
+        .build();
+
+    let shutdown_token = dispatcher.shutdown_token();
+    tokio::spawn(async move {
+        wait_for_shutdown_signal().await;
+        log::info!("Shutting down gracefully");
+        match shutdown_token.shutdown() {
+            Ok(shutdown) => shutdown.await,
+            Err(err) => log::error!("Failed to stop the dispatcher: {}", err),
         }
     });
 
-    Command::repl(bot_copy.clone(), answer).await;
-    send_to_all(&bot_copy, "The bot is shutting down.").await;
+    dispatcher.dispatch().await;
+    send_to_all(&bot_copy, &state, "The bot is shutting down.").await;
     log::info!("Stopping bot...");
 
-    log::info!("Writing todo.txt...");
-    let todo_list = TODO_LIST.lock().await;
-    let json = to_string(&*todo_list).unwrap();
-    std::fs::write("todo.json", json).unwrap();
+    log::info!("Writing cities.json...");
+    write_json(
+        &config.data_path("cities.json"),
+        &*state.city_list.lock().await,
+    );
 
-    log::info!("Writing users list...");
-    let users_list = USERS_LIST.lock().await.clone();
-    let content = users_list
-        .iter()
-        .map(|user| user.to_string())
-        .collect::<Vec<String>>()
-        .join("\n");
-    std::fs::write("users.txt", content).expect("Unable to write file");
+    log::info!("Writing timezones.json...");
+    write_json(
+        &config.data_path("timezones.json"),
+        &*state.timezones.lock().await,
+    );
+
+    log::info!("Writing metrics.json...");
+    write_json(
+        &config.data_path("metrics.json"),
+        &*state.command_counts.lock().await,
+    );
+
+    log::info!("Writing activity.json...");
+    write_json(
+        &config.data_path("activity.json"),
+        &*state.chat_activity.lock().await,
+    );
 }
 
-async fn send_to_all(bot: &Bot, msg: &str) {
-    for user in USERS_LIST.lock().await.iter() {
-        bot.send_message(*user, msg).await.unwrap();
+fn read_json<T: DeserializeOwned + Default>(path: &Path) -> T {
+    match std::fs::read_to_string(path) {
+        Ok(content) => match from_str(&content) {
+            Ok(value) => value,
+            Err(err) => {
+                log::error!("Failed to parse {}: {}", path.display(), err);
+                back_up_corrupt_file(path);
+                T::default()
+            }
+        },
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            log::info!("{} not found", path.display());
+            T::default()
+        }
+        Err(err) => {
+            log::error!("Failed to read {}: {}", path.display(), err);
+            T::default()
+        }
     }
 }
 
-#[derive(BotCommands, Clone, Debug)]
-#[command(
-    rename_rule = "lowercase",
-    description = "These commands are supported:"
-)]
-enum Command {
-    #[command(description = "display this text.")]
-    Help,
-    #[command(description = "get a random cat image")]
-    Cat,
-    #[command(description = "get definition of the word")]
-    Define(String),
-    #[command(description = "get useless facts")]
-    Useless,
-    #[command(description = "get raw source of github file")]
-    Raw(String),
-    #[command(description = "returns current weather status")]
-    Weather,
-    #[command(description = "roll a dice")]
-    Dice,
-    #[command(description = "toss a coin")]
-    Coin,
-    #[command(description = "add to todo list")]
-    Todo(String),
-    #[command(description = "show contents of todo list")]
-    List,
+/// Moves an unreadable file to `<path>.bak` so it isn't overwritten and can
+/// be fixed by hand.
+fn back_up_corrupt_file(path: &Path) {
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(".bak");
+    match std::fs::rename(path, &backup) {
+        Ok(()) => log::warn!("Moved {} to {:?}", path.display(), backup),
+        Err(err) => log::error!("Failed to back up {}: {}", path.display(), err),
+    }
 }
 
-async fn answer(bot: Bot, msg: Message, cmd: Command) -> ResponseResult<()> {
-    log::info!("Got command {:?}", cmd);
+fn write_json<T: Serialize>(path: &Path, value: &T) {
+    let json = to_string(value).unwrap();
+    if let Err(err) = atomic_write(path, json) {
+        log::error!("Failed to write {}: {}", path.display(), err);
+    }
+}
 
-    if !USERS_LIST.lock().await.contains(&msg.chat.id) {
-        USERS_LIST.lock().await.insert(msg.chat.id);
-        bot.send_message(
-            msg.chat.id,
-            format!(
-                "Hi {}!",
-                (msg.from().expect("Invalid user").username.clone()).expect("Invalid string")
-            ),
+/// Resolves on Ctrl-C, or on SIGTERM where available.
+async fn wait_for_shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result.expect("Failed to listen for ^C"),
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c()
+        .await
+        .expect("Failed to listen for ^C");
+}
+
+/// Loads the state kept in the database, importing the legacy
+/// `todo.json`/`users.txt` files the first time the database is created and
+/// any JSON files left from before their data moved to the database.
+async fn load_state(config: &Config, state: &State, fresh_db: bool) -> rusqlite::Result<()> {
+    let (
+        users,
+        unsubscribed,
+        todos,
+        personal_todo_chats,
+        alarms,
+        countdowns,
+        nicknames,
+        languages,
+        weather_formats,
+    ) = {
+        let mut conn = state.db.lock().unwrap();
+        if fresh_db {
+            log::info!("Importing todo.json and users.txt into the database");
+            db::import(
+                &mut conn,
+                &read_chat_ids(&config.data_path("users.txt")),
+                &read_chat_ids(&config.data_path("unsubscribed.txt")),
+                &read_legacy_todos(&config.data_path("todo.json")),
+            )?;
+        }
+        import_legacy_json(
+            &config.data_path("personal_todo_chats.json"),
+            |chats: HashSet<ChatId>| {
+                for chat_id in &chats {
+                    db::set_personal_todos(&conn, *chat_id, true)?;
+                }
+                Ok(())
+            },
+        )?;
+        import_legacy_json(
+            &config.data_path("alarms.json"),
+            |alarms: HashMap<ChatId, Vec<Alarm>>| {
+                for (chat_id, alarms) in &alarms {
+                    db::save_alarms(&mut conn, *chat_id, alarms)?;
+                }
+                Ok(())
+            },
+        )?;
+        import_legacy_json(
+            &config.data_path("countdowns.json"),
+            |countdowns: HashMap<ChatId, Vec<Countdown>>| {
+                for (chat_id, countdowns) in &countdowns {
+                    db::save_countdowns(&mut conn, *chat_id, countdowns)?;
+                }
+                Ok(())
+            },
+        )?;
+        import_legacy_json(
+            &config.data_path("nicknames.json"),
+            |nicknames: HashMap<ChatId, String>| {
+                for (chat_id, name) in &nicknames {
+                    db::set_nickname(&conn, *chat_id, name)?;
+                }
+                Ok(())
+            },
+        )?;
+        import_legacy_json(
+            &config.data_path("languages.json"),
+            |languages: HashMap<ChatId, String>| {
+                for (chat_id, language) in &languages {
+                    db::set_language(&conn, *chat_id, Some(language))?;
+                }
+                Ok(())
+            },
+        )?;
+        import_legacy_json(
+            &config.data_path("weather_formats.json"),
+            |formats: HashMap<ChatId, String>| {
+                for (chat_id, format) in &formats {
+                    db::set_weather_format(&conn, *chat_id, Some(format))?;
+                }
+                Ok(())
+            },
+        )?;
+        let (users, unsubscribed) = db::load_users(&conn)?;
+        (
+            users,
+            unsubscribed,
+            db::load_todos(&conn)?,
+            db::load_personal_todo_chats(&conn)?,
+            db::load_alarms(&conn)?,
+            db::load_countdowns(&conn)?,
+            db::load_nicknames(&conn)?,
+            db::load_languages(&conn)?,
+            db::load_weather_formats(&conn)?,
         )
-        .await?;
+    };
+    *state.users_list.lock().await = users;
+    *state.unsubscribed_list.lock().await = unsubscribed;
+    *state.todo_list.lock().await = todos;
+    *state.personal_todo_chats.lock().await = personal_todo_chats;
+    *state.alarms.lock().await = alarms;
+    *state.countdowns.lock().await = countdowns;
+    *state.nicknames.lock().await = nicknames;
+    *state.languages.lock().await = languages;
+    *state.weather_formats.lock().await = weather_formats;
+    Ok(())
+}
+
+/// Hands the contents of a JSON file from before its data moved to the
+/// database to `import`, then renames it to `<path>.imported` so that only
+/// happens once.
+fn import_legacy_json<T: DeserializeOwned + Default>(
+    path: &Path,
+    import: impl FnOnce(T) -> rusqlite::Result<()>,
+) -> rusqlite::Result<()> {
+    if !path.exists() {
+        return Ok(());
     }
+    log::info!("Importing {} into the database", path.display());
+    import(read_json(path))?;
+    let mut imported = path.as_os_str().to_owned();
+    imported.push(".imported");
+    if let Err(err) = std::fs::rename(path, &imported) {
+        log::error!("Failed to rename {}: {}", path.display(), err);
+    }
+    Ok(())
+}
 
-    match cmd {
-        Command::Help => {
-            bot.send_message(
-                msg.chat.id,
-                format!(
-                    "Hi {} !\n\nThis Bot was made by <b>Herr Das</b>\n\n{}",
-                    msg.from().expect("No user found").first_name.clone(),
-                    Command::descriptions()
-                ),
-            )
-            .parse_mode(ParseMode::Html)
-            .await?
+fn read_legacy_todos(path: &Path) -> HashMap<ChatId, Vec<TodoItem>> {
+    match std::fs::read_to_string(path) {
+        Ok(content) => {
+            if let Ok(todos) = from_str::<HashMap<ChatId, Vec<TodoItem>>>(&content) {
+                return todos;
+            }
+            match from_str::<HashMap<ChatId, Vec<String>>>(&content) {
+                Ok(tasks) => {
+                    log::info!("Migrating {} from the plain string format", path.display());
+                    tasks
+                        .into_iter()
+                        .map(|(chat_id, tasks)| {
+                            (chat_id, tasks.into_iter().map(TodoItem::new).collect())
+                        })
+                        .collect()
+                }
+                Err(err) => {
+                    log::error!("Failed to parse {}: {}", path.display(), err);
+                    back_up_corrupt_file(path);
+                    HashMap::new()
+                }
+            }
         }
-        Command::Cat => {
-            if let Ok(url) = get_cat_image().await {
-                bot.send_photo(
-                    msg.chat.id,
-                    InputFile::url(Url::parse(&url).expect("Incorrect url")),
-                )
-                .await?
-            } else {
-                bot.send_message(msg.chat.id, "Failed to fetch cat image.")
-                    .await?
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            log::info!("{} not found", path.display());
+            HashMap::new()
+        }
+        Err(err) => {
+            log::error!("Failed to read {}: {}", path.display(), err);
+            HashMap::new()
+        }
+    }
+}
+
+/// Writes the todos of `key` to the database.
+async fn save_todos(state: &State, key: TodoKey) {
+    let todo_list = state.todo_list.lock().await;
+    let items = todo_list.get(&key).map_or(&[][..], |items| items);
+    if let Err(err) = db::save_todos(&mut state.db.lock().unwrap(), key, items) {
+        log::error!("Failed to save todos of {}: {}", key.chat, err);
+    }
+}
+
+fn save_personal_todos(state: &State, chat_id: ChatId, personal: bool) {
+    if let Err(err) = db::set_personal_todos(&state.db.lock().unwrap(), chat_id, personal) {
+        log::error!("Failed to save the todo mode of {}: {}", chat_id, err);
+    }
+}
+
+fn save_alarms(state: &State, chat_id: ChatId, alarms: &[Alarm]) {
+    if let Err(err) = db::save_alarms(&mut state.db.lock().unwrap(), chat_id, alarms) {
+        log::error!("Failed to save alarms of {}: {}", chat_id, err);
+    }
+}
+
+fn save_countdowns(state: &State, chat_id: ChatId, countdowns: &[Countdown]) {
+    if let Err(err) = db::save_countdowns(&mut state.db.lock().unwrap(), chat_id, countdowns) {
+        log::error!("Failed to save countdowns of {}: {}", chat_id, err);
+    }
+}
+
+fn save_nickname(state: &State, chat_id: ChatId, name: &str) {
+    if let Err(err) = db::set_nickname(&state.db.lock().unwrap(), chat_id, name) {
+        log::error!("Failed to save the name of {}: {}", chat_id, err);
+    }
+}
+
+fn save_language(state: &State, chat_id: ChatId, language: Option<&str>) {
+    if let Err(err) = db::set_language(&state.db.lock().unwrap(), chat_id, language) {
+        log::error!("Failed to save the language of {}: {}", chat_id, err);
+    }
+}
+
+fn save_weather_format(state: &State, chat_id: ChatId, format: Option<&str>) {
+    if let Err(err) = db::set_weather_format(&state.db.lock().unwrap(), chat_id, format) {
+        log::error!("Failed to save the weather format of {}: {}", chat_id, err);
+    }
+}
+
+fn save_user(state: &State, chat_id: ChatId, subscribed: bool) {
+    if let Err(err) = db::set_user(&state.db.lock().unwrap(), chat_id, subscribed) {
+        log::error!("Failed to save user {}: {}", chat_id, err);
+    }
+}
+
+fn read_chat_ids(path: &Path) -> HashSet<ChatId> {
+    match std::fs::read_to_string(path) {
+        Ok(content) => content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .filter_map(|line| match line.parse::<i64>() {
+                Ok(id) => Some(ChatId(id)),
+                Err(_) => {
+                    log::warn!("Skipping invalid chat id '{}' in {}", line, path.display());
+                    None
+                }
+            })
+            .collect(),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            log::info!("{} not found", path.display());
+            HashSet::new()
+        }
+        Err(err) => {
+            log::error!("Failed to read {}: {}", path.display(), err);
+            HashSet::new()
+        }
+    }
+}
+
+/// Writes to a temporary file first and renames it over `path`, so a crash
+/// mid-write never leaves a truncated file behind.
+fn atomic_write(path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> io::Result<()> {
+    let path = path.as_ref();
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    std::fs::write(&tmp_path, contents)?;
+    std::fs::rename(&tmp_path, path)
+}
+
+/// Adds the tasks of /todo input. Unless `force` is set, tasks matching a
+/// pending one are skipped with a note.
+async fn add_todos(
+    bot: &Bot,
+    state: &State,
+    chat_id: ChatId,
+    key: TodoKey,
+    input: &str,
+    force: bool,
+    recurring: bool,
+) -> ResponseResult<Message> {
+    let tasks: Result<Vec<TodoItem>, String> = split_tasks(input)
+        .iter()
+        .map(|task| {
+            let (text, due) = parse_due(task)?;
+            Ok(TodoItem {
+                due,
+                recurring,
+                ..TodoItem::new(text)
+            })
+        })
+        .collect();
+    let tasks = match tasks {
+        Ok(tasks) if tasks.is_empty() => {
+            return bot
+                .send_message(chat_id, "Usage: /todo <task>; <another task>")
+                .await
+        }
+        Ok(tasks) => tasks,
+        Err(err) => return send_retrying(bot.send_message(chat_id, err)).await,
+    };
+
+    let mut todo_list = state.todo_list.lock().await;
+    let user_todo_list = todo_list.entry(key).or_insert_with(Vec::new);
+    let mut added = Vec::new();
+    let mut duplicates = Vec::new();
+    for task in tasks {
+        let existing = user_todo_list
+            .iter()
+            .position(|other| !other.done && other.text.to_lowercase() == task.text.to_lowercase());
+        match existing {
+            Some(i) if !force => duplicates.push((i + 1, task.text)),
+            _ => {
+                added.push(task.text.clone());
+                user_todo_list.push(task);
             }
         }
-        Command::Define(word) => {
-            let url = format!("https://api.dictionaryapi.dev/api/v2/entries/en/{}", word);
-            let resp = reqwest::get(&url).await?;
-            let json: serde_json::Value = resp.json().await?;
-            let mut content = String::new();
-            for meaning in json[0]["meanings"].as_array().unwrap() {
-                content.push_str(&format!(
-                    "{}\n",
-                    meaning["definitions"][0]["definition"].as_str().unwrap()
-                ));
+    }
+    drop(todo_list);
+    if !added.is_empty() {
+        log::info!("Adding {:?} to todo list", added);
+        save_todos(state, key).await;
+    }
+
+    let content = todo_confirmation(&added, &duplicates);
+    send_long(bot, chat_id, &content, Some(ParseMode::Html)).await
+}
+
+/// The /todo reply listing what was added and which tasks already existed.
+fn todo_confirmation(added: &[String], duplicates: &[(usize, String)]) -> String {
+    let mut content = match added {
+        [] => String::new(),
+        [task] => format!("Added <u>{}</u> to todo list", escape(task)),
+        _ => {
+            let mut content = format!("Added {} tasks.", added.len());
+            for task in added {
+                content.push_str(&format!("\n<u>{}</u>", escape(task)));
             }
-            bot.send_message(msg.chat.id, content).await?
+            content
         }
-        Command::Useless => {
-            let resp = reqwest::get("https://uselessfacts.jsph.pl/random.json?language=en").await?;
-            let json: serde_json::Value = resp.json().await?;
-            bot.send_message(msg.chat.id, json["text"].as_str().unwrap())
-                .await?
+    };
+    for (position, text) in duplicates {
+        if !content.is_empty() {
+            content.push('\n');
         }
-        Command::Raw(file) => {
-            let content = file
-                .replace("github.com", "raw.githubusercontent.com")
-                .replace("/blob/", "/");
-            bot.send_message(msg.chat.id, content).await?
+        content.push_str(&format!(
+            "<u>{}</u> already exists at position {}. Use /todoforce to add it anyway.",
+            escape(text),
+            position
+        ));
+    }
+    content
+}
+
+/// Renders the list soonest-due first, undated tasks last, while keeping each
+/// task's number so /done and friends still refer to the right one.
+fn render_todo_list(tasks: &[TodoItem], today: NaiveDate) -> String {
+    let mut ordered: Vec<(usize, &TodoItem)> = tasks.iter().enumerate().collect();
+    ordered.sort_by_key(|(_, task)| (task.due.is_none(), task.due));
+    let mut content = "<u>Todo list:</u>\n".to_string();
+    for (i, task) in ordered {
+        let mut line = escape(&task.text);
+        if task.recurring {
+            line.insert_str(0, "🔁 ");
         }
-        Command::Weather => {
-            let resp = reqwest::get("https://wttr.in/Hyderabad?format=%l:+%c+%t+%p+%m").await?;
-            let content = resp.text().await?;
-            bot.send_message(msg.chat.id, content).await?
-        }
-        Command::Dice => bot.send_dice(msg.chat.id).await?,
-        Command::Coin => bot.send_message(msg.chat.id, "🪙").await?,
-        Command::Todo(task) => {
-            log::info!("Adding '{}' to todo list", task);
-            let mut todo_list = TODO_LIST.lock().await;
-            let user_todo_list = todo_list.entry(msg.chat.id).or_insert_with(Vec::new);
-            user_todo_list.push(task.clone());
-            bot.send_message(msg.chat.id, format!("Added <u>{}</u> to todo list", task))
-                .parse_mode(ParseMode::Html)
-                .await?
+        if let Some(due) = task.due {
+            line.push_str(&format!(" (due {})", due));
         }
-        Command::List => {
-            let mut content = "<u>Todo list:</u>\n".to_string();
-            for (i, task) in (TODO_LIST.lock().await)[&msg.chat.id].iter().enumerate() {
-                content.push_str(&format!("{}. {}\n", i + 1, task));
+        if task.done {
+            content.push_str(&format!("{}. <s>{}</s>\n", i + 1, line));
+        } else if task.due.is_some_and(|due| due < today) {
+            content.push_str(&format!("{}. ⚠️ {}\n", i + 1, line));
+        } else {
+            content.push_str(&format!("{}. {}\n", i + 1, line));
+        }
+    }
+    content
+}
+
+/// Every todo list for the admin's /listall, grouped by chat.
+fn render_all_todos(todo_list: &HashMap<TodoKey, Vec<TodoItem>>) -> String {
+    let mut lists: Vec<(&TodoKey, &Vec<TodoItem>)> = todo_list
+        .iter()
+        .filter(|(_, tasks)| !tasks.is_empty())
+        .collect();
+    if lists.is_empty() {
+        return "Nobody has any todos.".to_string();
+    }
+    lists.sort_by_key(|(key, _)| (key.chat.0, key.user.map(|user| user.0)));
+    let mut content = "<u>All todos:</u>".to_string();
+    for (key, tasks) in lists {
+        match key.user {
+            Some(user) => content.push_str(&format!("\n\n<b>{} (user {})</b>", key.chat, user)),
+            None => content.push_str(&format!("\n\n<b>{}</b>", key.chat)),
+        }
+        for (i, task) in tasks.iter().enumerate() {
+            if task.done {
+                content.push_str(&format!("\n{}. <s>{}</s>", i + 1, escape(&task.text)));
+            } else {
+                content.push_str(&format!("\n{}. {}", i + 1, escape(&task.text)));
+            }
+        }
+    }
+    content
+}
+
+/// Sends wttr.in's PNG weather report of `city`.
+async fn send_weather_image(
+    bot: &Bot,
+    state: &State,
+    config: &Config,
+    chat_id: ChatId,
+    city: &str,
+) -> ResponseResult<Message> {
+    let image =
+        match fetch_with_retry(&state.http, &config.weather_png_url(city), FETCH_ATTEMPTS).await {
+            Ok(resp) if resp.status().is_success() => resp.bytes().await.map_err(BoxError::from),
+            Ok(resp) => Err(format!("wttr.in responded with {}", resp.status()).into()),
+            Err(err) => Err(err.into()),
+        };
+    match image {
+        Ok(image) => {
+            send_retrying(
+                bot.send_photo(chat_id, InputFile::memory(image).file_name("weather.png")),
+            )
+            .await
+        }
+        Err(err) => {
+            log::error!("Failed to fetch weather image for '{}': {}", city, err);
+            send_retrying(bot.send_message(
+                chat_id,
+                format!("Couldn't get a weather image for {}.", city),
+            ))
+            .await
+        }
+    }
+}
+
+/// The wttr.in `%` codes accepted by /setweatherformat.
+const WEATHER_FORMAT_CODES: &str = "cCxhtfwlmMpPoDSzsdu";
+
+/// Checks a /setweatherformat string, only allowing known `%` codes and
+/// plain separators so it can't inject other query parameters. Spaces become
+/// `+` as wttr.in expects.
+fn validate_weather_format(format: &str) -> Result<String, String> {
+    const USAGE: &str = "Usage: /setweatherformat <format>, e.g. /setweatherformat %l:+%t+%w. \
+        Allowed codes are %c %C %x %h %t %f %w %l %m %M %p %P %o %D %S %z %s %d %u";
+    if format.is_empty() || format.chars().count() > 100 {
+        return Err(USAGE.to_string());
+    }
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '%' => match chars.next() {
+                Some(code) if WEATHER_FORMAT_CODES.contains(code) => {}
+                _ => return Err(USAGE.to_string()),
+            },
+            c if c.is_alphanumeric() || " +:|,-/()".contains(c) => {}
+            _ => {
+                return Err(format!(
+                    "'{}' isn't allowed in a weather format. {}",
+                    c, USAGE
+                ))
             }
-            bot.send_message(msg.chat.id, content)
-                .parse_mode(ParseMode::Html)
-                .await?
         }
+    }
+    Ok(format.replace(' ', "+"))
+}
+
+/// Splits a trailing `png` option off /weather input.
+fn split_png_flag(input: &str) -> (&str, bool) {
+    let input = input.trim();
+    if input.eq_ignore_ascii_case("png") {
+        return ("", true);
+    }
+    match input.rsplit_once(char::is_whitespace) {
+        Some((city, flag)) if flag.eq_ignore_ascii_case("png") => (city.trim(), true),
+        _ => (input, false),
+    }
+}
+
+/// Splits a trailing `@YYYY-MM-DD` due date off a task. Trailing mentions
+/// like `@mom` are left as part of the text.
+fn parse_due(task: &str) -> Result<(String, Option<NaiveDate>), String> {
+    let (text, last) = task.rsplit_once(char::is_whitespace).unwrap_or(("", task));
+    let Some(due) = last
+        .strip_prefix('@')
+        .filter(|due| due.starts_with(|c: char| c.is_ascii_digit()))
+    else {
+        return Ok((task.to_string(), None));
     };
+    match NaiveDate::parse_from_str(due, "%Y-%m-%d") {
+        Ok(date) if !text.trim().is_empty() => Ok((text.trim().to_string(), Some(date))),
+        Ok(_) => Err("Usage: /todo <task> @YYYY-MM-DD".to_string()),
+        Err(_) => Err(format!(
+            "'{}' isn't a valid due date, use @YYYY-MM-DD like @2024-06-01.",
+            due
+        )),
+    }
+}
 
-    Ok(())
+/// Turns a github.com file url into its raw.githubusercontent.com one, or
+/// `None` when `input` isn't a link to a file on github.com.
+fn github_raw_url(input: &str) -> Option<Url> {
+    let url = Url::parse(input.trim()).ok()?;
+    if !matches!(url.scheme(), "http" | "https")
+        || !matches!(url.host_str(), Some("github.com" | "www.github.com"))
+    {
+        return None;
+    }
+    let segments: Vec<&str> = url.path_segments()?.collect();
+    match segments.as_slice() {
+        [owner, repo, "blob", branch, path @ ..]
+            if !path.is_empty() && path.iter().all(|segment| !segment.is_empty()) =>
+        {
+            let raw = format!(
+                "https://raw.githubusercontent.com/{}/{}/{}/{}",
+                owner,
+                repo,
+                branch,
+                path.join("/")
+            );
+            Url::parse(&raw).ok()
+        }
+        _ => None,
+    }
+}
+
+/// Reverts `action` on `tasks`. Completed tasks are found by their creation
+/// time, since their position may have changed since.
+fn undo(tasks: &mut Vec<TodoItem>, action: UndoAction) -> String {
+    match action {
+        UndoAction::Completed(previous) => {
+            let task = tasks
+                .iter_mut()
+                .find(|task| task.created == previous.created && task.text == previous.text);
+            match task {
+                Some(task) => {
+                    *task = previous;
+                    format!("Restored {}", task.text)
+                }
+                None => "That task no longer exists.".to_string(),
+            }
+        }
+        UndoAction::Cleared(mut cleared) => {
+            let restored = cleared.len();
+            cleared.append(tasks);
+            *tasks = cleared;
+            format!("Restored {} tasks.", restored)
+        }
+    }
+}
+
+/// Splits /todo input on semicolons and newlines into separate tasks.
+fn split_tasks(input: &str) -> Vec<String> {
+    input
+        .split([';', '\n'])
+        .map(str::trim)
+        .filter(|task| !task.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Lists the tasks containing `query`, ignoring case, numbered by their
+/// position in the full list.
+fn search_todos(tasks: &[TodoItem], query: &str) -> Option<String> {
+    let query = query.to_lowercase();
+    let matches: Vec<String> = tasks
+        .iter()
+        .enumerate()
+        .filter(|(_, task)| task.text.to_lowercase().contains(&query))
+        .map(|(i, task)| format!("{}. {}", i + 1, task.text))
+        .collect();
+    if matches.is_empty() {
+        None
+    } else {
+        Some(matches.join("\n"))
+    }
+}
+
+/// One button per pending task, tapping it sends `done:<index>` back, with
+/// `:<user id>` appended for personal lists so only their owner can tap.
+fn todo_keyboard(tasks: &[TodoItem], owner: Option<UserId>) -> Option<InlineKeyboardMarkup> {
+    const BUTTONS_PER_ROW: usize = 5;
+    let buttons: Vec<_> = tasks
+        .iter()
+        .enumerate()
+        .filter(|(_, task)| !task.done)
+        .map(|(i, _)| {
+            let data = match owner {
+                Some(owner) => format!("done:{}:{}", i, owner),
+                None => format!("done:{}", i),
+            };
+            InlineKeyboardButton::callback(format!("✓ {}", i + 1), data)
+        })
+        .collect();
+    if buttons.is_empty() {
+        return None;
+    }
+    Some(InlineKeyboardMarkup::new(
+        buttons
+            .chunks(BUTTONS_PER_ROW)
+            .map(|row| row.to_vec())
+            .collect::<Vec<_>>(),
+    ))
 }
 
-async fn get_cat_image() -> Result<String, Error> {
-    let resp = reqwest::get("https://api.thecatapi.com/v1/images/search").await?;
-    let images: Vec<serde_json::Value> = resp.json().await?;
-    Ok(images[0]["url"].as_str().unwrap().to_string())
+fn weekly_summary(tasks: &[TodoItem], now: DateTime<Local>) -> String {
+    let week_ago = now - chrono::Duration::days(7);
+    let added = tasks.iter().filter(|task| task.created >= week_ago).count();
+    let completed = tasks
+        .iter()
+        .filter(|task| {
+            task.completed
+                .is_some_and(|completed| completed >= week_ago)
+        })
+        .count();
+    let untracked = tasks
+        .iter()
+        .filter(|task| task.done && task.completed.is_none())
+        .count();
+
+    let mut content = format!(
+        "This week you added {} tasks and completed {}.",
+        added, completed
+    );
+    if untracked > 0 {
+        content.push_str(&format!(
+            " {} tasks were completed before completion times were recorded.",
+            untracked
+        ));
+    }
+    content
+}
+
+fn flip_coin(rng: &mut impl rand::Rng, heads_chance: f64) -> &'static str {
+    if rng.random_bool(heads_chance) {
+        "Heads"
+    } else {
+        "Tails"
+    }
+}
+
+/// Parses the inclusive range of /random, 1 to 6 when none is given.
+fn parse_range(input: &str) -> Result<(i64, i64), &'static str> {
+    const USAGE: &str = "Usage: /random <low> <high>, e.g. /random 1 100";
+    match input.split_whitespace().collect::<Vec<_>>().as_slice() {
+        [] => Ok((1, 6)),
+        [low, high] => match (low.parse::<i64>(), high.parse::<i64>()) {
+            (Ok(low), Ok(high)) if low <= high => Ok((low, high)),
+            (Ok(_), Ok(_)) => Err("The low end has to be at most the high end."),
+            _ => Err(USAGE),
+        },
+        _ => Err(USAGE),
+    }
+}
+
+const MAX_QR_TEXT: usize = 1000;
+
+/// Renders `text` as a PNG QR code.
+fn render_qr(text: &str) -> Result<Vec<u8>, BoxError> {
+    let image = qrcode::QrCode::new(text.as_bytes())?
+        .render::<image::Luma<u8>>()
+        .min_dimensions(256, 256)
+        .build();
+    let mut png = io::Cursor::new(Vec::new());
+    image.write_to(&mut png, image::ImageFormat::Png)?;
+    Ok(png.into_inner())
+}
+
+/// Splits `Question? | Option A | Option B` into the question and its options.
+fn parse_poll(input: &str) -> Result<(String, Vec<String>), String> {
+    const USAGE: &str = "Usage: /poll Question? | Option A | Option B";
+    let mut parts = input.split('|').map(|part| part.trim().to_string());
+    let question = parts.next().unwrap_or_default();
+    let options: Vec<String> = parts.collect();
+    if question.is_empty() || options.iter().any(|option| option.is_empty()) {
+        return Err(USAGE.to_string());
+    }
+    if !(2..=10).contains(&options.len()) {
+        return Err(format!("A poll needs between 2 and 10 options. {}", USAGE));
+    }
+    Ok((question, options))
+}
+
+/// Parses a 1-based task number into an index into a list of `len` tasks,
+/// or returns the message to reply with.
+fn parse_task_index(input: &str, len: usize, usage: &str) -> Result<usize, String> {
+    match input.trim().parse::<usize>() {
+        Ok(i) if i >= 1 && i <= len => Ok(i - 1),
+        Ok(_) if len == 0 => Err("Your todo list is empty.".to_string()),
+        Ok(_) => Err(format!(
+            "There is no task with that number. Pick one between 1 and {}.",
+            len
+        )),
+        Err(_) => Err(format!("Usage: {}", usage)),
+    }
+}
+
+/// Sends `text` as several messages when it exceeds Telegram's length limit,
+/// returning the last message sent.
+async fn send_long(
+    bot: &Bot,
+    chat_id: ChatId,
+    text: &str,
+    parse_mode: Option<ParseMode>,
+) -> ResponseResult<Message> {
+    let mut chunks = split_message(text);
+    if parse_mode == Some(ParseMode::Html) {
+        chunks = balance_html_chunks(chunks);
+    }
+    let mut chunks = chunks.into_iter().peekable();
+    loop {
+        let mut request = bot.send_message(chat_id, chunks.next().unwrap_or_default());
+        if let Some(parse_mode) = parse_mode {
+            request = request.parse_mode(parse_mode);
+        }
+        let message = send_retrying(request).await?;
+        if chunks.peek().is_none() {
+            return Ok(message);
+        }
+    }
+}
+
+/// Sends `request`, and when Telegram's flood control answers with RetryAfter
+/// waits the given time and tries once more.
+async fn send_retrying<R>(request: R) -> Result<Output<R>, RequestError>
+where
+    R: Request<Err = RequestError>,
+{
+    match request.send_ref().await {
+        Err(RequestError::RetryAfter(delay)) => {
+            log::warn!("Telegram asked to retry after {:?}", delay);
+            sleep(delay).await;
+            request.send_ref().await
+        }
+        result => result,
+    }
+}
+
+/// Splits `text` on line boundaries into chunks that fit in one message.
+/// Lines that are too long on their own are cut, but never inside an HTML
+/// tag or entity.
+fn split_message(text: &str) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+    let mut current_len = 0;
+    for mut line in text.split_inclusive('\n') {
+        let line_len = line.chars().count();
+        if current_len + line_len > MESSAGE_LIMIT && !current.is_empty() {
+            chunks.push(std::mem::take(&mut current));
+            current_len = 0;
+        }
+        while line.chars().count() > MESSAGE_LIMIT {
+            let end = html_safe_cut(line, MESSAGE_LIMIT);
+            chunks.push(line[..end].to_string());
+            line = &line[end..];
+        }
+        current.push_str(line);
+        current_len += line.chars().count();
+    }
+    if !current.is_empty() || chunks.is_empty() {
+        chunks.push(current);
+    }
+    chunks
+}
+
+/// Byte index at most `limit` chars into `line` that doesn't fall inside a
+/// `<tag>` or `&entity;`.
+fn html_safe_cut(line: &str, limit: usize) -> usize {
+    let mut end = line
+        .char_indices()
+        .nth(limit)
+        .map_or(line.len(), |(i, _)| i);
+    let head = &line[..end];
+    let open_tag = head.rfind('<').filter(|&i| !head[i..].contains('>'));
+    let open_entity = head.rfind('&').filter(|&i| !head[i..].contains(';'));
+    if let Some(start) = open_tag.into_iter().chain(open_entity).min() {
+        if start > 0 {
+            end = start;
+        }
+    }
+    end
+}
+
+/// Closes the HTML tags still open at the end of each chunk and reopens them
+/// at the start of the next, so every chunk parses on its own. Telegram's
+/// length limit doesn't count tags, so this never makes a chunk too long.
+fn balance_html_chunks(chunks: Vec<String>) -> Vec<String> {
+    let mut open_tags: Vec<String> = Vec::new();
+    let mut balanced = Vec::new();
+    for chunk in chunks {
+        let mut content = open_tags.concat();
+        content.push_str(&chunk);
+        let mut rest = chunk.as_str();
+        while let Some(start) = rest.find('<') {
+            let Some(len) = rest[start..].find('>') else {
+                break;
+            };
+            let tag = &rest[start..=start + len];
+            if tag.starts_with("</") {
+                let name = tag_name(tag);
+                if let Some(i) = open_tags.iter().rposition(|open| tag_name(open) == name) {
+                    open_tags.remove(i);
+                }
+            } else {
+                open_tags.push(tag.to_string());
+            }
+            rest = &rest[start + len + 1..];
+        }
+        for tag in open_tags.iter().rev() {
+            content.push_str(&format!("</{}>", tag_name(tag)));
+        }
+        balanced.push(content);
+    }
+    balanced
+}
+
+/// The element name of an opening or closing tag, e.g. `a` for `<a href="">`.
+fn tag_name(tag: &str) -> &str {
+    tag.trim_start_matches(['<', '/'])
+        .trim_end_matches('>')
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+}
+
+/// Delay between wttr.in requests while sending the morning greetings.
+const GREETING_FETCH_DELAY: Duration = Duration::from_millis(500);
+
+/// Today's date in the timezone of `chat_id`.
+async fn user_today(state: &State, chat_id: ChatId) -> NaiveDate {
+    user_now(state.timezones.lock().await.get(&chat_id).copied()).date()
+}
+
+/// Current wall clock time in `timezone`, or in the server's timezone for
+/// users who never set one.
+fn user_now(timezone: Option<Tz>) -> NaiveDateTime {
+    user_time(Utc::now(), timezone)
+}
+
+/// Wall clock time of `time` in `timezone`, like `user_now`.
+fn user_time(time: DateTime<Utc>, timezone: Option<Tz>) -> NaiveDateTime {
+    match timezone {
+        Some(timezone) => time.with_timezone(&timezone).naive_local(),
+        None => time.with_timezone(&Local).naive_local(),
+    }
+}
+
+/// The alarms going off in `minutes`, counted since the epoch. At most a
+/// day's worth of minutes is checked, so no alarm rings twice.
+fn due_alarms(
+    alarms: &HashMap<ChatId, Vec<Alarm>>,
+    timezones: &HashMap<ChatId, Tz>,
+    minutes: RangeInclusive<i64>,
+) -> Vec<(ChatId, String)> {
+    const MINUTES_PER_DAY: i64 = 24 * 60;
+    let start = *minutes.start().max(&(minutes.end() - MINUTES_PER_DAY + 1));
+    let mut ringing = Vec::new();
+    for minute in start..=*minutes.end() {
+        let Some(time) = DateTime::from_timestamp(minute * 60, 0) else {
+            continue;
+        };
+        for (chat_id, alarms) in alarms {
+            let now = user_time(time, timezones.get(chat_id).copied());
+            for alarm in alarms {
+                if alarm.time.hour() == now.hour() && alarm.time.minute() == now.minute() {
+                    ringing.push((*chat_id, alarm.text.clone()));
+                }
+            }
+        }
+    }
+    ringing
+}
+
+fn format_user_time(time: DateTime<Utc>, timezone: Option<Tz>) -> String {
+    match timezone {
+        Some(timezone) => time
+            .with_timezone(&timezone)
+            .format("%H:%M:%S %Z")
+            .to_string(),
+        None => time.with_timezone(&Local).format("%H:%M:%S").to_string(),
+    }
+}
+
+/// Fills in the GREETING_TEMPLATE placeholders.
+fn render_greeting(template: &str, weather: &str, name: &str, todos: &str) -> String {
+    template
+        .replace("{weather}", weather)
+        .replace("{name}", name)
+        .replace("{todos}", todos)
+}
+
+/// Sends each of `users` the weather for their own city. Each city and format
+/// is only fetched once per run.
+async fn send_greetings(bot: &Bot, config: &Config, state: &State, users: &[ChatId]) {
+    let mut weather_by_url: HashMap<String, String> = HashMap::new();
+    for &user in users {
+        let city = state
+            .city_list
+            .lock()
+            .await
+            .get(&user)
+            .cloned()
+            .unwrap_or_else(|| config.default_city.clone());
+        let format = state.weather_formats.lock().await.get(&user).cloned();
+        let url = config.weather_url(&city, format.as_deref());
+        let weather = match weather_by_url.get(&url) {
+            Some(weather) => weather.clone(),
+            None => {
+                sleep(GREETING_FETCH_DELAY).await;
+                let weather = match fetch_with_retry(&state.http, &url, FETCH_ATTEMPTS).await {
+                    Ok(resp) => resp.text().await,
+                    Err(err) => Err(err),
+                };
+                match weather {
+                    Ok(weather) => {
+                        weather_by_url.insert(url, weather.clone());
+                        weather
+                    }
+                    Err(err) => {
+                        log::error!("Failed to fetch weather for '{}': {}", city, err);
+                        continue;
+                    }
+                }
+            }
+        };
+        let todos = state
+            .todo_list
+            .lock()
+            .await
+            .get(&TodoKey::shared(user))
+            .map(|tasks| digest_todos(tasks))
+            .unwrap_or_else(|| digest_todos(&[]));
+        let name = state
+            .nicknames
+            .lock()
+            .await
+            .get(&user)
+            .cloned()
+            .unwrap_or_else(|| "there".to_string());
+        let greeting = truncate_message(&render_greeting(
+            &config.greeting_template,
+            weather.trim_end(),
+            &name,
+            &todos,
+        ));
+        if let Err(err) = send_retrying(bot.send_message(user, greeting)).await {
+            log::error!("Failed to send greeting to {}: {}", user, err);
+        }
+    }
+}
+
+/// Marks recurring todos completed before `today` as pending again, returning
+/// whether any changed.
+fn reset_recurring(tasks: &mut [TodoItem], timezone: Option<Tz>, today: NaiveDate) -> bool {
+    let mut changed = false;
+    for task in tasks.iter_mut().filter(|task| task.recurring && task.done) {
+        let completed = task.completed.map(|completed| match timezone {
+            Some(timezone) => completed.with_timezone(&timezone).date_naive(),
+            None => completed.date_naive(),
+        });
+        if completed.is_none_or(|completed| completed < today) {
+            task.done = false;
+            task.completed = None;
+            changed = true;
+        }
+    }
+    changed
+}
+
+/// The pending todos shown in the morning greeting.
+fn digest_todos(tasks: &[TodoItem]) -> String {
+    const DIGEST_TODOS: usize = 10;
+    let pending: Vec<(usize, &TodoItem)> = tasks
+        .iter()
+        .enumerate()
+        .filter(|(_, task)| !task.done)
+        .collect();
+    if pending.is_empty() {
+        return "No todos for today!".to_string();
+    }
+    let mut content = "Your todo list is:".to_string();
+    for (i, task) in pending.iter().take(DIGEST_TODOS) {
+        content.push_str(&format!("\n{}. {}", i + 1, task.text));
+    }
+    if pending.len() > DIGEST_TODOS {
+        content.push_str(&format!("\n...and {} more", pending.len() - DIGEST_TODOS));
+    }
+    content
+}
+
+/// How many broadcast messages are in flight at once, low enough to stay
+/// within Telegram's rate limits.
+const BROADCAST_CONCURRENCY: usize = 10;
+
+struct BroadcastSummary {
+    sent: usize,
+    failed: usize,
+}
+
+/// Sends `msg` to every subscribed user. A failed send doesn't stop the
+/// broadcast, and users who blocked the bot are dropped from the users list.
+async fn send_to_all(bot: &Bot, state: &State, msg: &str) -> BroadcastSummary {
+    let users: Vec<ChatId> = state.users_list.lock().await.iter().copied().collect();
+    let mut results = futures::stream::iter(users)
+        .map(|user| async move { (user, send_retrying(bot.send_message(user, msg)).await) })
+        .buffer_unordered(BROADCAST_CONCURRENCY);
+
+    let mut summary = BroadcastSummary { sent: 0, failed: 0 };
+    let mut unreachable = Vec::new();
+    while let Some((user, result)) = results.next().await {
+        match result {
+            Ok(_) => summary.sent += 1,
+            Err(err) => {
+                log::error!("Failed to send message to {}: {}", user, err);
+                summary.failed += 1;
+                if matches!(
+                    err,
+                    RequestError::Api(
+                        ApiError::BotBlocked
+                            | ApiError::BotKicked
+                            | ApiError::BotKickedFromSupergroup
+                            | ApiError::UserDeactivated
+                    )
+                ) {
+                    unreachable.push(user);
+                }
+            }
+        }
+    }
+
+    if !unreachable.is_empty() {
+        let mut users_list = state.users_list.lock().await;
+        for user in unreachable {
+            log::info!("Removing unreachable user {}", user);
+            users_list.remove(&user);
+            if let Err(err) = db::remove_user(&state.db.lock().unwrap(), user) {
+                log::error!("Failed to remove user {}: {}", user, err);
+            }
+        }
+    }
+    summary
+}
+
+#[derive(BotCommands, Clone, Debug)]
+#[command(
+    rename_rule = "lowercase",
+    description = "These commands are supported:"
+)]
+enum Command {
+    #[command(description = "start using the bot")]
+    Start,
+    #[command(description = "display this text.")]
+    Help,
+    #[command(description = "show who made this bot")]
+    About,
+    #[command(description = "show which build of the bot is running")]
+    Version,
+    #[command(description = "get a random cat image, optionally of a breed as /cat bengal")]
+    Cat(String),
+    #[command(description = "get up to 5 random cat images")]
+    Cats(String),
+    #[command(description = "get a random dog image")]
+    Dog,
+    #[command(
+        description = "get definition of the word, optionally in another language as /define fr bonjour"
+    )]
+    Define(String),
+    #[command(description = "get useless facts")]
+    Useless,
+    #[command(description = "show the USD price of a coin, use as /crypto btc")]
+    Crypto(String),
+    #[command(
+        description = "translate text, e.g. /translate es hello world (target language code first)"
+    )]
+    Translate(String),
+    #[command(description = "get the top news headlines")]
+    News,
+    #[command(
+        description = "list the next public holidays, optionally for a country as /holidays de"
+    )]
+    Holidays(String),
+    #[command(description = "summarize a long text")]
+    Summarize(String),
+    #[command(description = "get an inspirational quote")]
+    Quote,
+    #[command(description = "get a random joke")]
+    Joke,
+    #[command(description = "get raw source of github file")]
+    Raw(String),
+    #[command(
+        description = "returns current weather status, optionally for the given city. Add png for an image"
+    )]
+    Weather(String),
+    #[command(description = "get a 3 day forecast, optionally for the given city")]
+    Forecast(String),
+    #[command(description = "set the city used by /weather")]
+    SetCity(String),
+    #[command(
+        description = "customize /weather with wttr.in % codes, e.g. /setweatherformat %l:+%t+%w, or reset it with default"
+    )]
+    SetWeatherFormat(String),
+    #[command(description = "set your timezone, e.g. /settimezone Europe/Berlin")]
+    SetTimezone(String),
+    #[command(description = "set the name the bot calls you")]
+    SetName(String),
+    #[command(description = "set the language of /define and /useless, e.g. /setlang de")]
+    SetLang(String),
+    #[command(description = "roll a dice")]
+    Dice,
+    #[command(description = "toss a coin")]
+    Coin,
+    #[command(description = "pick a random number, use as /random 1 100")]
+    Random(String),
+    #[command(description = "create a poll, use as /poll Question? | Option A | Option B")]
+    Poll(String),
+    #[command(description = "turn text into a QR code")]
+    Qr(String),
+    #[command(description = "add to todo list")]
+    Todo(String),
+    #[command(description = "add to todo list even if the task already exists")]
+    TodoForce(String),
+    #[command(description = "add a todo that comes back every day after it's done")]
+    TodoDaily(String),
+    #[command(description = "show contents of todo list")]
+    List,
+    #[command(description = "show the todos containing some text")]
+    Search(String),
+    #[command(description = "mark a todo as done by its number")]
+    Done(String),
+    #[command(description = "move a todo to the top of the list by its number")]
+    Prioritize(String),
+    #[command(description = "move a todo to another position, use as /move 5 1")]
+    Move(String),
+    #[command(description = "change the text of a todo, use as /edit 2 new text")]
+    Edit(String),
+    #[command(
+        description = "give each group member their own todo list, use as /mode personal or /mode shared"
+    )]
+    Mode(String),
+    #[command(description = "download your todos as a JSON file, send the file back to import it")]
+    Export,
+    #[command(description = "summarize the todos added and completed this week")]
+    Weekly,
+    #[command(description = "undo the last /done or /clear")]
+    Undo,
+    #[command(description = "remove every todo, use as /clear confirm")]
+    Clear(String),
+    #[command(description = "get reminded later, e.g. /remind 30m buy milk")]
+    Remind(String),
+    #[command(description = "get reminded at a time, e.g. /remindme tomorrow 9am call dentist")]
+    RemindMe(String),
+    #[command(description = "postpone the last reminder, e.g. /snooze 10m")]
+    Snooze(String),
+    #[command(description = "list your pending reminders")]
+    Reminders,
+    #[command(description = "cancel a reminder by its number in /reminders")]
+    CancelReminder(String),
+    #[command(description = "get a message every day, e.g. /alarm 07:30 take meds")]
+    Alarm(String),
+    #[command(description = "list your daily alarms")]
+    Alarms,
+    #[command(description = "delete an alarm by its number in /alarms")]
+    DeleteAlarm(String),
+    #[command(description = "count the days until an event, e.g. /countdown 2030-01-01 New Year")]
+    Countdown(String),
+    #[command(description = "list your countdowns")]
+    Countdowns,
+    #[command(description = "send a message to every subscriber (admin only)")]
+    Broadcast(String),
+    #[command(description = "schedule a broadcast, use as /remindall 1h message (admin only)")]
+    RemindAll(String),
+    #[command(description = "show bot usage statistics, per user for the admin")]
+    Stats,
+    #[command(description = "show how often each command was used")]
+    Metrics,
+    #[command(description = "show every chat's todos (admin only)")]
+    ListAll,
+    #[command(description = "send feedback to the bot's owner")]
+    Feedback(String),
+    #[command(description = "measure the round trip time to Telegram")]
+    Ping,
+    #[command(description = "show your chat id and subscription status")]
+    WhoAmI,
+    #[command(description = "reply with the exact text received")]
+    Echo(String),
+    #[command(description = "receive daily messages")]
+    Subscribe,
+    #[command(description = "stop receiving daily messages")]
+    Unsubscribe,
+}
+
+/// The /help sections. Commands missing here still show up under "Other".
+const HELP_SECTIONS: &[(&str, &[&str])] = &[
+    (
+        "👋 Basics",
+        &["/start", "/help", "/about", "/version", "/whoami", "/ping"],
+    ),
+    (
+        "🐱 Fun",
+        &[
+            "/cat", "/cats", "/dog", "/joke", "/quote", "/useless", "/dice", "/coin", "/random",
+            "/poll", "/qr", "/echo",
+        ],
+    ),
+    (
+        "📝 Todos",
+        &[
+            "/todo",
+            "/todoforce",
+            "/tododaily",
+            "/list",
+            "/search",
+            "/done",
+            "/prioritize",
+            "/move",
+            "/edit",
+            "/undo",
+            "/clear",
+            "/weekly",
+            "/export",
+            "/mode",
+        ],
+    ),
+    (
+        "⏰ Reminders",
+        &[
+            "/remind",
+            "/remindme",
+            "/snooze",
+            "/reminders",
+            "/cancelreminder",
+            "/alarm",
+            "/alarms",
+            "/deletealarm",
+            "/countdown",
+            "/countdowns",
+        ],
+    ),
+    (
+        "🌤 Info",
+        &[
+            "/weather",
+            "/forecast",
+            "/define",
+            "/translate",
+            "/crypto",
+            "/news",
+            "/holidays",
+            "/summarize",
+            "/raw",
+            "/stats",
+            "/metrics",
+        ],
+    ),
+    (
+        "⚙️ Settings",
+        &[
+            "/setcity",
+            "/setweatherformat",
+            "/settimezone",
+            "/setname",
+            "/setlang",
+            "/subscribe",
+            "/unsubscribe",
+            "/feedback",
+        ],
+    ),
+    ("🔧 Admin", &["/broadcast", "/remindall", "/listall"]),
+];
+
+/// HTML help message listing the commands by HELP_SECTIONS category.
+fn help_text() -> String {
+    let commands = Command::bot_commands();
+    let mut sections: Vec<String> = HELP_SECTIONS
+        .iter()
+        .map(|(title, names)| format!("<b>{}:</b> {}", title, names.join(" ")))
+        .collect();
+    let other: Vec<&str> = commands
+        .iter()
+        .map(|command| command.command.as_str())
+        .filter(|name| !HELP_SECTIONS.iter().any(|(_, names)| names.contains(name)))
+        .collect();
+    if !other.is_empty() {
+        sections.push(format!("<b>Other:</b> {}", other.join(" ")));
+    }
+    sections.join("\n")
+}
+
+/// Lowercase name of the command as typed by users, e.g. `define`.
+fn command_name(cmd: &Command) -> String {
+    let debug = format!("{:?}", cmd);
+    debug
+        .split(|c: char| !c.is_alphanumeric())
+        .next()
+        .unwrap_or_default()
+        .to_lowercase()
+}
+
+const MAX_IMPORT_SIZE: u32 = 1024 * 1024;
+
+/// Whether `document` looks like a file made by /export.
+fn is_todo_export(document: &teloxide::types::Document) -> bool {
+    document
+        .file_name
+        .as_deref()
+        .is_some_and(|name| name.starts_with("todos-") && name.ends_with(".json"))
+}
+
+/// Loads todos from an uploaded /export file. They're added after the
+/// existing ones, or replace them when the caption is "replace".
+async fn import_todos(
+    bot: Bot,
+    msg: Message,
+    config: Arc<Config>,
+    state: Arc<State>,
+) -> ResponseResult<()> {
+    let Some(document) = msg.document() else {
+        return Ok(());
+    };
+    log::info!(
+        "[chat {}, {}] Got todo import {:?}",
+        msg.chat.id,
+        sender_label(msg.from()),
+        document.file_name
+    );
+    if !config.is_allowed(msg.chat.id) {
+        send_retrying(bot.send_message(msg.chat.id, "Access denied")).await?;
+        return Ok(());
+    }
+    if document.file.size > MAX_IMPORT_SIZE {
+        send_retrying(bot.send_message(msg.chat.id, "That file is too big to import.")).await?;
+        return Ok(());
+    }
+    let file = send_retrying(bot.get_file(&document.file.id)).await?;
+    let mut content = Vec::new();
+    if let Err(err) = bot.download_file(&file.path, &mut content).await {
+        log::error!("Failed to download {}: {}", file.path, err);
+        send_retrying(bot.send_message(msg.chat.id, "Failed to download the file.")).await?;
+        return Ok(());
+    }
+    let tasks = match serde_json::from_slice::<Vec<TodoItem>>(&content) {
+        Ok(tasks) => tasks,
+        Err(err) => {
+            log::info!("Rejected todo import from {}: {}", msg.chat.id, err);
+            send_retrying(bot.send_message(
+                msg.chat.id,
+                "That file isn't a valid todo export, make one with /export.",
+            ))
+            .await?;
+            return Ok(());
+        }
+    };
+
+    let replace = msg
+        .caption()
+        .is_some_and(|caption| caption.trim() == "replace");
+    let imported = tasks.len();
+    let key = todo_key_for(&state, &msg).await;
+    let mut todo_list = state.todo_list.lock().await;
+    let user_todo_list = todo_list.entry(key).or_insert_with(Vec::new);
+    if replace {
+        *user_todo_list = tasks;
+    } else {
+        user_todo_list.extend(tasks);
+    }
+    drop(todo_list);
+    save_todos(&state, key).await;
+    send_retrying(bot.send_message(
+        msg.chat.id,
+        format!(
+            "Imported {} tasks{}.",
+            imported,
+            if replace {
+                ", replacing your old list"
+            } else {
+                ""
+            }
+        ),
+    ))
+    .await?;
+    Ok(())
+}
+
+/// Parses `done:<index>` callback data and its optional list owner.
+fn parse_done_callback(data: &str) -> Option<(usize, Option<UserId>)> {
+    let mut parts = data.strip_prefix("done:")?.split(':');
+    let index = parts.next()?.parse().ok()?;
+    let owner = match parts.next() {
+        Some(owner) => Some(UserId(owner.parse().ok()?)),
+        None => None,
+    };
+    Some((index, owner))
+}
+
+/// The todo list `msg` refers to.
+async fn todo_key_for(state: &State, msg: &Message) -> TodoKey {
+    let personal = !msg.chat.is_private()
+        && state
+            .personal_todo_chats
+            .lock()
+            .await
+            .contains(&msg.chat.id);
+    match msg.from() {
+        Some(user) if personal => TodoKey {
+            chat: msg.chat.id,
+            user: Some(user.id),
+        },
+        _ => TodoKey::shared(msg.chat.id),
+    }
+}
+
+/// Handles taps on the /list buttons, marking the task done and refreshing
+/// the list message.
+async fn handle_callback(
+    bot: Bot,
+    q: CallbackQuery,
+    config: Arc<Config>,
+    state: Arc<State>,
+) -> ResponseResult<()> {
+    let data = q.data.as_deref().and_then(parse_done_callback);
+    let (Some((index, owner)), Some(message)) = (data, q.message.as_ref()) else {
+        send_retrying(bot.answer_callback_query(q.id)).await?;
+        return Ok(());
+    };
+    if !config.is_allowed(message.chat.id) {
+        send_retrying(bot.answer_callback_query(q.id).text("Access denied")).await?;
+        return Ok(());
+    }
+    if owner.is_some_and(|owner| owner != q.from.id) {
+        send_retrying(
+            bot.answer_callback_query(q.id)
+                .text("That isn't your todo list."),
+        )
+        .await?;
+        return Ok(());
+    }
+    let chat_id = message.chat.id;
+    let key = TodoKey {
+        chat: chat_id,
+        user: owner,
+    };
+    log::info!(
+        "[chat {}, {}] Got callback {:?}",
+        chat_id,
+        sender_label(Some(&q.from)),
+        q.data
+    );
+    let today = user_today(&state, chat_id).await;
+
+    let mut todo_list = state.todo_list.lock().await;
+    let tasks = todo_list.entry(key).or_insert_with(Vec::new);
+    let reply = match tasks.get_mut(index) {
+        Some(task) if !task.done => {
+            state
+                .last_undo
+                .lock()
+                .await
+                .insert(key, UndoAction::Completed(task.clone()));
+            task.done = true;
+            task.completed = Some(Local::now());
+            log::info!("Marking '{}' as done", task.text);
+            format!("Completed {}", task.text)
+        }
+        Some(_) => "That task is already done.".to_string(),
+        None => "That task no longer exists.".to_string(),
+    };
+    let content = render_todo_list(tasks, today);
+    let keyboard = todo_keyboard(tasks, key.user);
+    drop(todo_list);
+    save_todos(&state, key).await;
+
+    send_retrying(bot.answer_callback_query(q.id).text(reply)).await?;
+    refresh_list_message(&bot, chat_id, message.id, &content, keyboard).await
+}
+
+/// Rewrites a /list message in place. Lists that were split over several
+/// messages only get their buttons updated.
+async fn refresh_list_message(
+    bot: &Bot,
+    chat_id: ChatId,
+    message_id: MessageId,
+    content: &str,
+    keyboard: Option<InlineKeyboardMarkup>,
+) -> ResponseResult<()> {
+    let keyboard = keyboard.unwrap_or_else(|| InlineKeyboardMarkup::new(Vec::<Vec<_>>::new()));
+    let result = if split_message(content).len() == 1 {
+        send_retrying(
+            bot.edit_message_text(chat_id, message_id, content)
+                .parse_mode(ParseMode::Html)
+                .reply_markup(keyboard),
+        )
+        .await
+    } else {
+        send_retrying(
+            bot.edit_message_reply_markup(chat_id, message_id)
+                .reply_markup(keyboard),
+        )
+        .await
+    };
+    match result {
+        Ok(_) | Err(RequestError::Api(ApiError::MessageNotModified)) => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+/// Runs a command, telling the user when it fails instead of leaving them
+/// without a reply.
+async fn answer(
+    bot: Bot,
+    msg: Message,
+    cmd: Command,
+    config: Arc<Config>,
+    state: Arc<State>,
+) -> ResponseResult<()> {
+    let chat_id = msg.chat.id;
+    let name = command_name(&cmd);
+    if let Err(err) = run_command(bot.clone(), msg, cmd, config, state).await {
+        log::error!("[chat {}] /{} failed: {}", chat_id, name, err);
+        // Replying would only run into the same flood limit again.
+        if let Some(RequestError::RetryAfter(_)) = err.downcast_ref::<RequestError>() {
+            return Ok(());
+        }
+        let reply = format!("Something went wrong: {}", err);
+        if let Err(err) = send_retrying(bot.send_message(chat_id, truncate_message(&reply))).await {
+            log::error!("Failed to report the error to {}: {}", chat_id, err);
+        }
+    }
+    Ok(())
+}
+
+async fn run_command(
+    bot: Bot,
+    msg: Message,
+    cmd: Command,
+    config: Arc<Config>,
+    state: Arc<State>,
+) -> Result<(), BoxError> {
+    log::info!(
+        "[chat {}, {}] Got command {:?}",
+        msg.chat.id,
+        sender_label(msg.from()),
+        cmd
+    );
+    if !config.is_allowed(msg.chat.id) {
+        log::info!("Denied access to {}", msg.chat.id);
+        send_retrying(bot.send_message(msg.chat.id, "Access denied")).await?;
+        return Ok(());
+    }
+    {
+        let mut last_command = state.last_command.lock().await;
+        let now = Instant::now();
+        if let Some(last) = last_command.get(&msg.chat.id) {
+            if now.duration_since(*last) < config.rate_limit {
+                log::info!("Rate limited {}", msg.chat.id);
+                drop(last_command);
+                send_retrying(bot.send_message(msg.chat.id, "Slow down!")).await?;
+                return Ok(());
+            }
+        }
+        last_command.insert(msg.chat.id, now);
+    }
+
+    *state
+        .command_counts
+        .lock()
+        .await
+        .entry(command_name(&cmd))
+        .or_insert(0) += 1;
+    {
+        let mut chat_activity = state.chat_activity.lock().await;
+        let activity = chat_activity.entry(msg.chat.id).or_default();
+        activity.commands += 1;
+        if msg.from().is_some() {
+            activity.name = Some(sender_label(msg.from()));
+        }
+    }
+    if let Some(command_log) = &state.command_log {
+        let line = format!(
+            "{}\t{}\t{}",
+            Local::now().to_rfc3339(),
+            msg.chat.id,
+            command_name(&cmd)
+        );
+        if command_log.send(line).is_err() {
+            log::error!("Command log is no longer running");
+        }
+    }
+
+    // First contact outside of /start, which does its own welcome.
+    if !matches!(cmd, Command::Start)
+        && !state.users_list.lock().await.contains(&msg.chat.id)
+        && !state.unsubscribed_list.lock().await.contains(&msg.chat.id)
+    {
+        state.users_list.lock().await.insert(msg.chat.id);
+        save_user(&state, msg.chat.id, true);
+        let nickname = state.nicknames.lock().await.get(&msg.chat.id).cloned();
+        send_retrying(bot.send_message(
+            msg.chat.id,
+            format!("Hi {}!", greeting_name(&msg, nickname.as_deref())),
+        ))
+        .await?;
+    }
+
+    let todo_key = todo_key_for(&state, &msg).await;
+
+    if is_network_bound(&cmd) {
+        send_typing(&bot, msg.chat.id).await;
+    }
+
+    match cmd {
+        Command::Start => {
+            let unsubscribed = state.unsubscribed_list.lock().await.contains(&msg.chat.id);
+            if !unsubscribed && state.users_list.lock().await.insert(msg.chat.id) {
+                log::info!("Onboarding {}", msg.chat.id);
+                save_user(&state, msg.chat.id, true);
+            }
+            let nickname = state.nicknames.lock().await.get(&msg.chat.id).cloned();
+            let mut content = format!(
+                "Hi {}! I can fetch the weather, keep your todos, send cat pictures and more.",
+                greeting_name(&msg, nickname.as_deref())
+            );
+            if unsubscribed {
+                content.push_str(
+                    "\n\nYou are unsubscribed from daily messages, use /subscribe to opt back in.",
+                );
+            } else {
+                content.push_str("\n\nYou will get a greeting with the weather every morning.");
+            }
+            content.push_str("\n\nUse /help to see every command.");
+            send_retrying(bot.send_message(msg.chat.id, content)).await?
+        }
+        Command::Help => {
+            let nickname = state.nicknames.lock().await.get(&msg.chat.id).cloned();
+            let help_name = greeting_name(&msg, nickname.as_deref());
+            send_retrying(
+                bot.send_message(
+                    msg.chat.id,
+                    format!("Hi {}!\n\n{}", escape(&help_name), help_text()),
+                )
+                .parse_mode(ParseMode::Html),
+            )
+            .await?
+        }
+        Command::About => {
+            send_retrying(
+                bot.send_message(
+                    msg.chat.id,
+                    format!(
+                        "This Bot was made by <b>{}</b>.",
+                        escape(&config.owner_name)
+                    ),
+                )
+                .parse_mode(ParseMode::Html),
+            )
+            .await?
+        }
+        Command::Version => {
+            send_retrying(bot.send_message(
+                msg.chat.id,
+                format!(
+                    "herr-jr {} ({})",
+                    env!("CARGO_PKG_VERSION"),
+                    option_env!("GIT_HASH").unwrap_or("unknown commit")
+                ),
+            ))
+            .await?
+        }
+        Command::Cat(breed) => {
+            let breed = breed.trim().to_lowercase();
+            let breed_id = state.cat_breeds.lock().await.get(&breed).cloned();
+            if !breed.is_empty() && breed_id.is_none() {
+                send_retrying(bot.send_message(
+                    msg.chat.id,
+                    format!("I don't know the breed '{}', here's a random cat.", breed),
+                ))
+                .await?;
+            }
+            let image =
+                get_fresh_cat_image(&state, &config, msg.chat.id, breed_id.as_deref()).await;
+            send_image(&bot, msg.chat.id, image, "cat image").await?
+        }
+        Command::Dog => {
+            send_image(
+                &bot,
+                msg.chat.id,
+                get_dog_image(&state.http, &config).await,
+                "dog image",
+            )
+            .await?
+        }
+        Command::Cats(count) => match count.trim().parse::<usize>() {
+            Ok(count) if count >= 1 => {
+                match get_cat_images(&state.http, &config, count.min(MAX_CATS), None).await {
+                    Ok(urls) => {
+                        let mut last = None;
+                        for url in urls {
+                            last = Some(
+                                send_retrying(bot.send_photo(msg.chat.id, InputFile::url(url)))
+                                    .await?,
+                            );
+                        }
+                        last.expect("get_cat_images returns at least one image")
+                    }
+                    Err(err) => {
+                        log::error!("Failed to fetch cat images: {}", err);
+                        send_retrying(bot.send_message(msg.chat.id, "Failed to fetch cat images."))
+                            .await?
+                    }
+                }
+            }
+            _ => {
+                send_retrying(bot.send_message(
+                    msg.chat.id,
+                    format!("Usage: /cats <number between 1 and {}>", MAX_CATS),
+                ))
+                .await?
+            }
+        },
+        Command::Define(input) if input.trim().is_empty() => {
+            send_retrying(bot.send_message(msg.chat.id, "Usage: /define [language] <word>")).await?
+        }
+        Command::Define(input) => {
+            let preferred = state.languages.lock().await.get(&msg.chat.id).cloned();
+            let default = supported_language(preferred.as_deref(), DICTIONARY_LANGUAGES);
+            let (language, word) = parse_define(&input, default.unwrap_or("en"));
+            let url = format!(
+                "{}/{}/{}",
+                config.dictionary_api_url,
+                language,
+                urlencoding::encode(word)
+            );
+            let resp = fetch_with_retry(&state.http, &url, FETCH_ATTEMPTS).await?;
+            let json: serde_json::Value = resp.json().await?;
+            match format_definitions(&json) {
+                Some(content) => {
+                    let message = send_long(&bot, msg.chat.id, &content, None).await?;
+                    match pronunciation_audio(&json) {
+                        Some(audio) => {
+                            match send_retrying(bot.send_audio(msg.chat.id, InputFile::url(audio)))
+                                .await
+                            {
+                                Ok(audio_message) => audio_message,
+                                Err(err) => {
+                                    log::error!("Failed to send pronunciation audio: {}", err);
+                                    message
+                                }
+                            }
+                        }
+                        None => message,
+                    }
+                }
+                None => {
+                    send_retrying(
+                        bot.send_message(
+                            msg.chat.id,
+                            format!("No definition found for '{}'.", word),
+                        ),
+                    )
+                    .await?
+                }
+            }
+        }
+        Command::Useless => {
+            let preferred = state.languages.lock().await.get(&msg.chat.id).cloned();
+            let language = supported_language(preferred.as_deref(), USELESS_FACTS_LANGUAGES);
+            let url = format!(
+                "{}?language={}",
+                config.useless_facts_url,
+                language.unwrap_or("en")
+            );
+            let resp = fetch_with_retry(&state.http, &url, FETCH_ATTEMPTS).await?;
+            let json: serde_json::Value = resp.json().await?;
+            let fact = json["text"].as_str().ok_or("useless fact has no text")?;
+            send_retrying(bot.send_message(msg.chat.id, fact)).await?
+        }
+        Command::News => match &config.news_api_key {
+            None => {
+                send_retrying(
+                    bot.send_message(msg.chat.id, "News is disabled, no NEWS_API_KEY is set."),
+                )
+                .await?
+            }
+            Some(api_key) => match get_headlines(&state.http, &config, api_key).await {
+                Ok(headlines) if headlines.is_empty() => {
+                    send_retrying(bot.send_message(msg.chat.id, "No headlines right now.")).await?
+                }
+                Ok(headlines) => {
+                    let mut content = "<u>Top headlines:</u>".to_string();
+                    for (i, (title, url)) in headlines.iter().enumerate() {
+                        content.push_str(&format!(
+                            "\n{}. <a href=\"{}\">{}</a>",
+                            i + 1,
+                            escape(url),
+                            escape(title)
+                        ));
+                    }
+                    send_retrying(
+                        bot.send_message(msg.chat.id, content)
+                            .parse_mode(ParseMode::Html)
+                            .disable_web_page_preview(true),
+                    )
+                    .await?
+                }
+                Err(err) => {
+                    log::error!("Failed to fetch headlines: {}", err);
+                    send_retrying(
+                        bot.send_message(msg.chat.id, "Failed to fetch the news, try again later."),
+                    )
+                    .await?
+                }
+            },
+        },
+        Command::Quote => match get_quote(&state.http, &config).await {
+            Ok((content, author)) => {
+                send_retrying(
+                    bot.send_message(msg.chat.id, format!("\"{}\"\n— {}", content, author)),
+                )
+                .await?
+            }
+            Err(err) => {
+                log::error!("Failed to fetch quote: {}", err);
+                send_retrying(bot.send_message(msg.chat.id, "Failed to fetch a quote.")).await?
+            }
+        },
+        Command::Joke => match get_joke(&state.http, &config).await {
+            Ok((setup, punchline)) => {
+                send_retrying(bot.send_message(msg.chat.id, setup)).await?;
+                sleep(Duration::from_secs(2)).await;
+                send_retrying(bot.send_message(msg.chat.id, punchline)).await?
+            }
+            Err(err) => {
+                log::error!("Failed to fetch joke: {}", err);
+                send_retrying(bot.send_message(msg.chat.id, "Failed to fetch a joke.")).await?
+            }
+        },
+        Command::Crypto(ticker) => match coin_id(&ticker) {
+            Some(id) => match get_coin_price(&state.http, &config, id).await {
+                Ok(price) => {
+                    send_retrying(bot.send_message(
+                        msg.chat.id,
+                        format!("{}: {}", ticker.trim().to_uppercase(), format_usd(price)),
+                    ))
+                    .await?
+                }
+                Err(err) => {
+                    log::error!("Failed to fetch the price of {}: {}", id, err);
+                    send_retrying(bot.send_message(msg.chat.id, "Failed to fetch the price."))
+                        .await?
+                }
+            },
+            None => {
+                let tickers: Vec<&str> = COINS.iter().map(|(ticker, _)| *ticker).collect();
+                send_retrying(bot.send_message(
+                    msg.chat.id,
+                    format!("Unknown coin. Try one of: {}", tickers.join(", ")),
+                ))
+                .await?
+            }
+        },
+        Command::Translate(input) => match input.trim().split_once(char::is_whitespace) {
+            Some((target, text)) if !text.trim().is_empty() => {
+                match translate(&state.http, &config, &target.to_lowercase(), text.trim()).await {
+                    Ok(translated) => {
+                        send_retrying(bot.send_message(msg.chat.id, translated)).await?
+                    }
+                    Err(err) => {
+                        log::error!("Failed to translate to '{}': {}", target, err);
+                        send_retrying(
+                            bot.send_message(msg.chat.id, format!("Couldn't translate: {}", err)),
+                        )
+                        .await?
+                    }
+                }
+            }
+            _ => {
+                send_retrying(bot.send_message(
+                    msg.chat.id,
+                    "Usage: /translate <language code> <text>, e.g. /translate es hello world",
+                ))
+                .await?
+            }
+        },
+        Command::Holidays(country) => {
+            let country = match country.trim() {
+                "" => config.default_country.to_uppercase(),
+                country => country.to_uppercase(),
+            };
+            if country.len() != 2 || !country.chars().all(|c| c.is_ascii_alphabetic()) {
+                send_retrying(bot.send_message(
+                    msg.chat.id,
+                    "Usage: /holidays [country code], e.g. /holidays de",
+                ))
+                .await?
+            } else {
+                match get_holidays(&state.http, &config, &country).await {
+                    Ok(Some(holidays)) if holidays.is_empty() => {
+                        send_retrying(bot.send_message(
+                            msg.chat.id,
+                            format!("No upcoming holidays in {}.", country),
+                        ))
+                        .await?
+                    }
+                    Ok(Some(holidays)) => {
+                        let mut content = format!("<u>Upcoming holidays in {}:</u>", country);
+                        for (date, name) in holidays {
+                            content.push_str(&format!(
+                                "\n<b>{}</b> {}",
+                                date.format("%a %d %b"),
+                                escape(&name)
+                            ));
+                        }
+                        send_retrying(
+                            bot.send_message(msg.chat.id, content)
+                                .parse_mode(ParseMode::Html),
+                        )
+                        .await?
+                    }
+                    Ok(None) => {
+                        send_retrying(bot.send_message(
+                            msg.chat.id,
+                            format!("I don't know the country code {}.", country),
+                        ))
+                        .await?
+                    }
+                    Err(err) => {
+                        log::error!("Failed to fetch holidays for {}: {}", country, err);
+                        send_retrying(
+                            bot.send_message(msg.chat.id, "Failed to fetch the holidays."),
+                        )
+                        .await?
+                    }
+                }
+            }
+        }
+        Command::Summarize(_) if config.summary_api_key.is_none() => {
+            send_retrying(bot.send_message(
+                msg.chat.id,
+                "Summaries are disabled, no SUMMARY_API_KEY is set.",
+            ))
+            .await?
+        }
+        Command::Summarize(text) if text.trim().is_empty() => {
+            send_retrying(bot.send_message(msg.chat.id, "Usage: /summarize <text>")).await?
+        }
+        Command::Summarize(text) => match summarize(&state.http, &config, text.trim()).await {
+            Ok(summary) => send_long(&bot, msg.chat.id, &summary, None).await?,
+            Err(err) => {
+                log::error!("Failed to summarize: {}", err);
+                send_retrying(bot.send_message(msg.chat.id, format!("Couldn't summarize: {}", err)))
+                    .await?
+            }
+        },
+        Command::Raw(file) => match github_raw_url(&file) {
+            None => {
+                send_retrying(bot.send_message(
+                    msg.chat.id,
+                    "Usage: /raw https://github.com/<owner>/<repo>/blob/<branch>/<path>",
+                ))
+                .await?
+            }
+            Some(url) => {
+                let resp = state.http.get(url.clone()).send().await?;
+                let is_text = resp
+                    .headers()
+                    .get(reqwest::header::CONTENT_TYPE)
+                    .and_then(|value| value.to_str().ok())
+                    .is_none_or(|value| value.starts_with("text/"));
+                if resp.status() == reqwest::StatusCode::NOT_FOUND {
+                    send_retrying(bot.send_message(msg.chat.id, "File not found.")).await?
+                } else if !resp.status().is_success() {
+                    send_retrying(bot.send_message(
+                        msg.chat.id,
+                        format!("GitHub responded with {}.", resp.status()),
+                    ))
+                    .await?
+                } else if !is_text {
+                    send_retrying(
+                        bot.send_message(msg.chat.id, format!("Not a text file, see {}", url)),
+                    )
+                    .await?
+                } else {
+                    match String::from_utf8(resp.bytes().await?.to_vec()) {
+                        Ok(content) if content.trim().is_empty() => {
+                            send_retrying(bot.send_message(msg.chat.id, "The file is empty."))
+                                .await?
+                        }
+                        Ok(content) => {
+                            send_retrying(bot.send_message(msg.chat.id, truncate_message(&content)))
+                                .await?
+                        }
+                        Err(_) => {
+                            send_retrying(
+                                bot.send_message(
+                                    msg.chat.id,
+                                    format!("Not a text file, see {}", url),
+                                ),
+                            )
+                            .await?
+                        }
+                    }
+                }
+            }
+        },
+        Command::Weather(city) if city.contains('\n') => {
+            send_retrying(bot.send_message(msg.chat.id, "Usage: /weather [city] [png]")).await?
+        }
+        Command::Weather(input) => {
+            let (city, png) = split_png_flag(&input);
+            let city = match city {
+                "" => state.city_list.lock().await.get(&msg.chat.id).cloned(),
+                city => Some(city.to_string()),
+            };
+            let name = city.as_deref().unwrap_or(&config.default_city);
+            if png {
+                send_weather_image(&bot, &state, &config, msg.chat.id, name).await?
+            } else {
+                let format = state
+                    .weather_formats
+                    .lock()
+                    .await
+                    .get(&msg.chat.id)
+                    .cloned();
+                let url = config.weather_url(name, format.as_deref());
+                let resp = fetch_with_retry(&state.http, &url, FETCH_ATTEMPTS).await?;
+                let mut content = resp.text().await?;
+                if city.is_none() {
+                    content.push_str(
+                        "\n\nShowing the default city. Run /setcity <name> first to use your own.",
+                    );
+                }
+                send_retrying(bot.send_message(msg.chat.id, content)).await?
+            }
+        }
+        Command::Forecast(city) => {
+            let city = match city.trim() {
+                "" => state
+                    .city_list
+                    .lock()
+                    .await
+                    .get(&msg.chat.id)
+                    .cloned()
+                    .unwrap_or_else(|| config.default_city.clone()),
+                city => city.to_string(),
+            };
+            let url = format!(
+                "{}/{}?format=j1",
+                config.weather_api_url,
+                urlencoding::encode(&city)
+            );
+            let resp = fetch_with_retry(&state.http, &url, FETCH_ATTEMPTS).await?;
+            let content = resp.text().await?;
+            match from_str::<Value>(&content)
+                .ok()
+                .and_then(|json| format_forecast(&json))
+            {
+                Some(forecast) => {
+                    send_retrying(
+                        bot.send_message(
+                            msg.chat.id,
+                            format!(
+                                "<u>Forecast for {}:</u>\n{}",
+                                escape(&city),
+                                escape(&forecast)
+                            ),
+                        )
+                        .parse_mode(ParseMode::Html),
+                    )
+                    .await?
+                }
+                None => {
+                    log::error!("Unexpected forecast response for '{}': {}", city, content);
+                    send_retrying(bot.send_message(
+                        msg.chat.id,
+                        format!("Couldn't get a forecast for {}.", city),
+                    ))
+                    .await?
+                }
+            }
+        }
+        Command::SetCity(city) => {
+            let city = city.trim().to_string();
+            if city.is_empty() {
+                send_retrying(bot.send_message(msg.chat.id, "Usage: /setcity <name>")).await?
+            } else {
+                log::info!("Setting city of {} to '{}'", msg.chat.id, city);
+                state
+                    .city_list
+                    .lock()
+                    .await
+                    .insert(msg.chat.id, city.clone());
+                send_retrying(
+                    bot.send_message(msg.chat.id, format!("City set to <u>{}</u>", escape(&city)))
+                        .parse_mode(ParseMode::Html),
+                )
+                .await?
+            }
+        }
+        Command::SetWeatherFormat(format) => {
+            let format = format.trim();
+            if format.eq_ignore_ascii_case("default") {
+                state.weather_formats.lock().await.remove(&msg.chat.id);
+                save_weather_format(&state, msg.chat.id, None);
+                send_retrying(bot.send_message(msg.chat.id, "Weather format reset to the default."))
+                    .await?
+            } else {
+                match validate_weather_format(format) {
+                    Ok(format) => {
+                        log::info!("Setting weather format of {} to '{}'", msg.chat.id, format);
+                        save_weather_format(&state, msg.chat.id, Some(&format));
+                        state
+                            .weather_formats
+                            .lock()
+                            .await
+                            .insert(msg.chat.id, format.clone());
+                        send_retrying(
+                            bot.send_message(
+                                msg.chat.id,
+                                format!("Weather format set to {}", format),
+                            ),
+                        )
+                        .await?
+                    }
+                    Err(err) => send_retrying(bot.send_message(msg.chat.id, err)).await?,
+                }
+            }
+        }
+        Command::SetTimezone(name) => {
+            match name.trim().parse::<Tz>() {
+                Ok(timezone) => {
+                    log::info!("Setting timezone of {} to {}", msg.chat.id, timezone);
+                    state.timezones.lock().await.insert(msg.chat.id, timezone);
+                    send_retrying(bot.send_message(
+                        msg.chat.id,
+                        format!(
+                            "Timezone set to {}. It is {} there now.",
+                            timezone,
+                            user_now(Some(timezone)).format("%H:%M")
+                        ),
+                    ))
+                    .await?
+                }
+                Err(_) => send_retrying(bot.send_message(
+                    msg.chat.id,
+                    "Unknown timezone. Use an IANA name like Asia/Kolkata or America/New_York, \
+                    see https://en.wikipedia.org/wiki/List_of_tz_database_time_zones",
+                ))
+                .await?,
+            }
+        }
+        Command::SetName(name) => {
+            let name = name.trim();
+            if name.is_empty() || name.contains('\n') {
+                send_retrying(bot.send_message(msg.chat.id, "Usage: /setname <name>")).await?
+            } else if name.chars().count() > MAX_NICKNAME_LEN {
+                send_retrying(bot.send_message(
+                    msg.chat.id,
+                    format!("Keep it under {} characters.", MAX_NICKNAME_LEN),
+                ))
+                .await?
+            } else {
+                log::info!("Setting name of {} to '{}'", msg.chat.id, name);
+                save_nickname(&state, msg.chat.id, name);
+                state
+                    .nicknames
+                    .lock()
+                    .await
+                    .insert(msg.chat.id, name.to_string());
+                send_retrying(
+                    bot.send_message(msg.chat.id, format!("I'll call you {} from now on.", name)),
+                )
+                .await?
+            }
+        }
+        Command::SetLang(language) => {
+            let language = language.trim();
+            if language.eq_ignore_ascii_case("default") {
+                state.languages.lock().await.remove(&msg.chat.id);
+                save_language(&state, msg.chat.id, None);
+                send_retrying(bot.send_message(msg.chat.id, "Language reset to English.")).await?
+            } else if let Some(language) = supported_language(
+                Some(language),
+                &[DICTIONARY_LANGUAGES, USELESS_FACTS_LANGUAGES].concat(),
+            ) {
+                log::info!("Setting language of {} to '{}'", msg.chat.id, language);
+                save_language(&state, msg.chat.id, Some(language));
+                state
+                    .languages
+                    .lock()
+                    .await
+                    .insert(msg.chat.id, language.to_string());
+                send_retrying(bot.send_message(
+                    msg.chat.id,
+                    format!(
+                        "Language set to {}. Commands that don't support it stay in English.",
+                        language
+                    ),
+                ))
+                .await?
+            } else {
+                send_retrying(bot.send_message(
+                    msg.chat.id,
+                    format!(
+                        "Usage: /setlang <code>, one of {} or default",
+                        DICTIONARY_LANGUAGES.join(", ")
+                    ),
+                ))
+                .await?
+            }
+        }
+        Command::Dice => {
+            let dice = send_retrying(bot.send_dice(msg.chat.id)).await?;
+            match dice.dice() {
+                Some(dice) => {
+                    // Give the animation time to land before revealing it.
+                    sleep(Duration::from_secs(4)).await;
+                    send_retrying(
+                        bot.send_message(msg.chat.id, format!("You rolled a {}!", dice.value)),
+                    )
+                    .await?
+                }
+                None => {
+                    log::warn!("Sent dice message has no dice value");
+                    dice
+                }
+            }
+        }
+        Command::Coin => {
+            let side = flip_coin(&mut rand::rng(), config.coin_heads_chance);
+            send_retrying(bot.send_message(msg.chat.id, "🪙")).await?;
+            sleep(Duration::from_secs(1)).await;
+            send_retrying(bot.send_message(msg.chat.id, side)).await?
+        }
+        Command::Random(input) => match parse_range(&input) {
+            Ok((low, high)) => {
+                let number = rand::rng().random_range(low..=high);
+                send_retrying(bot.send_message(msg.chat.id, number.to_string())).await?
+            }
+            Err(err) => send_retrying(bot.send_message(msg.chat.id, err)).await?,
+        },
+        Command::Qr(text) => {
+            let text = text.trim();
+            if text.is_empty() {
+                send_retrying(bot.send_message(msg.chat.id, "Usage: /qr <text>")).await?
+            } else if text.chars().count() > MAX_QR_TEXT {
+                send_retrying(bot.send_message(
+                    msg.chat.id,
+                    format!("That's too long, keep it under {} characters.", MAX_QR_TEXT),
+                ))
+                .await?
+            } else {
+                match render_qr(text) {
+                    Ok(png) => {
+                        send_retrying(
+                            bot.send_photo(msg.chat.id, InputFile::memory(png).file_name("qr.png")),
+                        )
+                        .await?
+                    }
+                    Err(err) => {
+                        log::error!("Failed to render QR code: {}", err);
+                        send_retrying(bot.send_message(msg.chat.id, "Failed to make a QR code."))
+                            .await?
+                    }
+                }
+            }
+        }
+        Command::Poll(input) => match parse_poll(&input) {
+            Ok((question, options)) => {
+                send_retrying(bot.send_poll(msg.chat.id, question, options)).await?
+            }
+            Err(err) => send_retrying(bot.send_message(msg.chat.id, err)).await?,
+        },
+        Command::Todo(input) => {
+            add_todos(&bot, &state, msg.chat.id, todo_key, &input, false, false).await?
+        }
+        Command::TodoForce(input) => {
+            add_todos(&bot, &state, msg.chat.id, todo_key, &input, true, false).await?
+        }
+        Command::TodoDaily(input) => {
+            add_todos(&bot, &state, msg.chat.id, todo_key, &input, false, true).await?
+        }
+        Command::List => {
+            let today = user_today(&state, msg.chat.id).await;
+            let content = state
+                .todo_list
+                .lock()
+                .await
+                .get(&todo_key)
+                .filter(|tasks| !tasks.is_empty())
+                .map(|tasks| {
+                    (
+                        render_todo_list(tasks, today),
+                        todo_keyboard(tasks, todo_key.user),
+                    )
+                });
+            match content {
+                Some((content, keyboard)) => {
+                    let message =
+                        send_long(&bot, msg.chat.id, &content, Some(ParseMode::Html)).await?;
+                    match keyboard {
+                        Some(keyboard) => {
+                            send_retrying(
+                                bot.edit_message_reply_markup(msg.chat.id, message.id)
+                                    .reply_markup(keyboard),
+                            )
+                            .await?
+                        }
+                        None => message,
+                    }
+                }
+                None => {
+                    send_retrying(bot.send_message(msg.chat.id, "Your todo list is empty.")).await?
+                }
+            }
+        }
+        Command::Search(query) => {
+            let query = query.trim();
+            if query.is_empty() {
+                send_retrying(bot.send_message(msg.chat.id, "Usage: /search <text>")).await?
+            } else {
+                let content = state
+                    .todo_list
+                    .lock()
+                    .await
+                    .get(&todo_key)
+                    .and_then(|tasks| search_todos(tasks, query));
+                match content {
+                    Some(content) => send_long(&bot, msg.chat.id, &content, None).await?,
+                    None => send_retrying(bot.send_message(msg.chat.id, "No matches")).await?,
+                }
+            }
+        }
+        Command::Done(index) => {
+            let mut todo_list = state.todo_list.lock().await;
+            let user_todo_list = todo_list.entry(todo_key).or_insert_with(Vec::new);
+            match parse_task_index(&index, user_todo_list.len(), "/done <task number>") {
+                Ok(i) if user_todo_list[i].done => {
+                    drop(todo_list);
+                    send_retrying(bot.send_message(msg.chat.id, "That task is already done."))
+                        .await?
+                }
+                Ok(i) => {
+                    let task = &mut user_todo_list[i];
+                    state
+                        .last_undo
+                        .lock()
+                        .await
+                        .insert(todo_key, UndoAction::Completed(task.clone()));
+                    task.done = true;
+                    task.completed = Some(Local::now());
+                    let text = task.text.clone();
+                    log::info!("Marking '{}' as done", text);
+                    drop(todo_list);
+                    save_todos(&state, todo_key).await;
+                    send_retrying(
+                        bot.send_message(
+                            msg.chat.id,
+                            format!("Completed <s>{}</s>", escape(&text)),
+                        )
+                        .parse_mode(ParseMode::Html),
+                    )
+                    .await?
+                }
+                Err(err) => {
+                    drop(todo_list);
+                    send_retrying(bot.send_message(msg.chat.id, err)).await?
+                }
+            }
+        }
+        Command::Prioritize(index) => {
+            let today = user_today(&state, msg.chat.id).await;
+            let mut todo_list = state.todo_list.lock().await;
+            let user_todo_list = todo_list.entry(todo_key).or_insert_with(Vec::new);
+            match parse_task_index(&index, user_todo_list.len(), "/prioritize <task number>") {
+                Ok(i) => {
+                    let task = user_todo_list.remove(i);
+                    log::info!("Moving '{}' to the top of the todo list", task.text);
+                    user_todo_list.insert(0, task);
+                    let content = render_todo_list(user_todo_list, today);
+                    drop(todo_list);
+                    save_todos(&state, todo_key).await;
+                    send_long(&bot, msg.chat.id, &content, Some(ParseMode::Html)).await?
+                }
+                Err(err) => {
+                    drop(todo_list);
+                    send_retrying(bot.send_message(msg.chat.id, err)).await?
+                }
+            }
+        }
+        Command::Move(input) => {
+            const USAGE: &str = "/move <task number> <new position>";
+            let today = user_today(&state, msg.chat.id).await;
+            let mut todo_list = state.todo_list.lock().await;
+            let user_todo_list = todo_list.entry(todo_key).or_insert_with(Vec::new);
+            let len = user_todo_list.len();
+            let indices = match input.split_whitespace().collect::<Vec<_>>().as_slice() {
+                [from, to] => parse_task_index(from, len, USAGE)
+                    .and_then(|from| Ok((from, parse_task_index(to, len, USAGE)?))),
+                _ => Err(format!("Usage: {}", USAGE)),
+            };
+            match indices {
+                Ok((from, to)) => {
+                    let task = user_todo_list.remove(from);
+                    log::info!("Moving '{}' to position {}", task.text, to + 1);
+                    user_todo_list.insert(to, task);
+                    let content = render_todo_list(user_todo_list, today);
+                    drop(todo_list);
+                    save_todos(&state, todo_key).await;
+                    send_long(&bot, msg.chat.id, &content, Some(ParseMode::Html)).await?
+                }
+                Err(err) => {
+                    drop(todo_list);
+                    send_retrying(bot.send_message(msg.chat.id, err)).await?
+                }
+            }
+        }
+        Command::Edit(input) => {
+            const USAGE: &str = "/edit <task number> <new text>";
+            let (index, text) = input
+                .trim()
+                .split_once(char::is_whitespace)
+                .unwrap_or((&input, ""));
+            let text = text.trim();
+            let today = user_today(&state, msg.chat.id).await;
+            let mut todo_list = state.todo_list.lock().await;
+            let user_todo_list = todo_list.entry(todo_key).or_insert_with(Vec::new);
+            match parse_task_index(index, user_todo_list.len(), USAGE) {
+                Ok(_) if text.is_empty() => {
+                    drop(todo_list);
+                    send_retrying(bot.send_message(msg.chat.id, format!("Usage: {}", USAGE)))
+                        .await?
+                }
+                Ok(i) => {
+                    log::info!("Renaming '{}' to '{}'", user_todo_list[i].text, text);
+                    user_todo_list[i].text = text.to_string();
+                    let content = render_todo_list(user_todo_list, today);
+                    drop(todo_list);
+                    save_todos(&state, todo_key).await;
+                    send_long(&bot, msg.chat.id, &content, Some(ParseMode::Html)).await?
+                }
+                Err(err) => {
+                    drop(todo_list);
+                    send_retrying(bot.send_message(msg.chat.id, err)).await?
+                }
+            }
+        }
+        Command::Mode(_) if msg.chat.is_private() => {
+            send_retrying(bot.send_message(
+                msg.chat.id,
+                "Todo lists in private chats are always personal.",
+            ))
+            .await?
+        }
+        Command::Mode(mode) => {
+            let mut personal_todo_chats = state.personal_todo_chats.lock().await;
+            let reply = match mode.trim() {
+                "personal" => {
+                    personal_todo_chats.insert(msg.chat.id);
+                    save_personal_todos(&state, msg.chat.id, true);
+                    "Everyone in this chat now has their own todo list."
+                }
+                "shared" => {
+                    personal_todo_chats.remove(&msg.chat.id);
+                    save_personal_todos(&state, msg.chat.id, false);
+                    "This chat now shares one todo list."
+                }
+                "" if personal_todo_chats.contains(&msg.chat.id) => {
+                    "Everyone in this chat has their own todo list. Use /mode shared to share one."
+                }
+                "" => {
+                    "This chat shares one todo list. Use /mode personal to give everyone their own."
+                }
+                _ => "Usage: /mode personal or /mode shared",
+            };
+            drop(personal_todo_chats);
+            log::info!("Todo mode of {} is now '{}'", msg.chat.id, mode.trim());
+            send_retrying(bot.send_message(msg.chat.id, reply)).await?
+        }
+        Command::Export => {
+            let json = state
+                .todo_list
+                .lock()
+                .await
+                .get(&todo_key)
+                .filter(|tasks| !tasks.is_empty())
+                .map(serde_json::to_vec_pretty);
+            match json {
+                Some(Ok(json)) => {
+                    send_retrying(bot.send_document(
+                        msg.chat.id,
+                        InputFile::memory(json).file_name(format!("todos-{}.json", msg.chat.id)),
+                    ))
+                    .await?
+                }
+                Some(Err(err)) => {
+                    log::error!("Failed to export todos of {}: {}", msg.chat.id, err);
+                    send_retrying(bot.send_message(msg.chat.id, "Failed to export your todos."))
+                        .await?
+                }
+                None => {
+                    send_retrying(
+                        bot.send_message(
+                            msg.chat.id,
+                            "Your todo list is empty, nothing to export.",
+                        ),
+                    )
+                    .await?
+                }
+            }
+        }
+        Command::Weekly => {
+            let content = state
+                .todo_list
+                .lock()
+                .await
+                .get(&todo_key)
+                .filter(|tasks| !tasks.is_empty())
+                .map(|tasks| weekly_summary(tasks, Local::now()));
+            send_retrying(bot.send_message(
+                msg.chat.id,
+                content.unwrap_or_else(|| "No todo history yet.".to_string()),
+            ))
+            .await?
+        }
+        Command::Undo => {
+            let action = state.last_undo.lock().await.remove(&todo_key);
+            let reply = match action {
+                Some(action) => {
+                    let mut todo_list = state.todo_list.lock().await;
+                    let tasks = todo_list.entry(todo_key).or_insert_with(Vec::new);
+                    let reply = undo(tasks, action);
+                    drop(todo_list);
+                    save_todos(&state, todo_key).await;
+                    reply
+                }
+                None => "Nothing to undo".to_string(),
+            };
+            send_retrying(bot.send_message(msg.chat.id, reply)).await?
+        }
+        Command::Clear(confirmation) => {
+            if confirmation.trim() != "confirm" {
+                send_retrying(bot.send_message(
+                    msg.chat.id,
+                    "This removes every task on your list. Run /clear confirm to proceed.",
+                ))
+                .await?
+            } else {
+                let tasks = state
+                    .todo_list
+                    .lock()
+                    .await
+                    .insert(todo_key, Vec::new())
+                    .unwrap_or_default();
+                let cleared = tasks.len();
+                if cleared > 0 {
+                    state
+                        .last_undo
+                        .lock()
+                        .await
+                        .insert(todo_key, UndoAction::Cleared(tasks));
+                }
+                log::info!("Cleared {} tasks of {}", cleared, msg.chat.id);
+                save_todos(&state, todo_key).await;
+                send_retrying(bot.send_message(msg.chat.id, format!("Cleared {} tasks.", cleared)))
+                    .await?
+            }
+        }
+        Command::Remind(input) => match parse_reminder(&input, REMIND_USAGE) {
+            Ok((delay, text)) => {
+                log::info!("Reminding {} in {:?}: '{}'", msg.chat.id, delay, text);
+                let fire_at = schedule_reminder(&bot, &state, msg.chat.id, delay, text).await;
+                let timezone = state.timezones.lock().await.get(&msg.chat.id).copied();
+                send_retrying(bot.send_message(
+                    msg.chat.id,
+                    format!(
+                        "I'll remind you at {}. Note that reminders are lost if the bot restarts.",
+                        format_user_time(fire_at, timezone)
+                    ),
+                ))
+                .await?
+            }
+            Err(err) => send_retrying(bot.send_message(msg.chat.id, err)).await?,
+        },
+        Command::RemindMe(input) => {
+            let timezone = state.timezones.lock().await.get(&msg.chat.id).copied();
+            match parse_remindme(&input, timezone) {
+                Ok((delay, text)) => {
+                    log::info!("Reminding {} in {:?}: '{}'", msg.chat.id, delay, text);
+                    let fire_at = schedule_reminder(&bot, &state, msg.chat.id, delay, text).await;
+                    send_retrying(bot.send_message(
+                        msg.chat.id,
+                        format!(
+                            "I'll remind you at {}. Note that reminders are lost if the bot restarts.",
+                            format_user_time(fire_at, timezone)
+                        ),
+                    ))
+                    .await?
+                }
+                Err(err) => send_retrying(bot.send_message(msg.chat.id, err)).await?,
+            }
+        }
+        Command::Snooze(input) => {
+            let delay = parse_duration(input.trim()).filter(|delay| *delay <= MAX_REMINDER_DELAY);
+            let last_fired = state
+                .last_fired_reminder
+                .lock()
+                .await
+                .get(&msg.chat.id)
+                .cloned();
+            match (delay, last_fired) {
+                (_, None) => {
+                    send_retrying(bot.send_message(msg.chat.id, "No reminder has gone off yet."))
+                        .await?
+                }
+                (None, Some(_)) => {
+                    send_retrying(bot.send_message(
+                        msg.chat.id,
+                        "Usage: /snooze <duration>, e.g. /snooze 10m. Durations take an s, m or h suffix and can be at most 24h.",
+                    ))
+                    .await?
+                }
+                (Some(delay), Some(text)) => {
+                    log::info!("Snoozing '{}' of {} for {:?}", text, msg.chat.id, delay);
+                    state.last_fired_reminder.lock().await.remove(&msg.chat.id);
+                    let fire_at = schedule_reminder(&bot, &state, msg.chat.id, delay, text).await;
+                    let timezone = state.timezones.lock().await.get(&msg.chat.id).copied();
+                    send_retrying(bot.send_message(
+                        msg.chat.id,
+                        format!("Snoozed until {}.", format_user_time(fire_at, timezone)),
+                    ))
+                    .await?
+                }
+            }
+        }
+        Command::Reminders => {
+            let timezone = state.timezones.lock().await.get(&msg.chat.id).copied();
+            let content = state
+                .reminders
+                .lock()
+                .await
+                .get(&msg.chat.id)
+                .filter(|pending| !pending.is_empty())
+                .map(|pending| {
+                    let mut content = "Upcoming reminders:".to_string();
+                    for (i, reminder) in pending.iter().enumerate() {
+                        content.push_str(&format!(
+                            "\n{}. {} at {}",
+                            i + 1,
+                            reminder.text,
+                            format_user_time(reminder.fire_at, timezone)
+                        ));
+                    }
+                    content
+                });
+            match content {
+                Some(content) => send_long(&bot, msg.chat.id, &content, None).await?,
+                None => {
+                    send_retrying(bot.send_message(msg.chat.id, "No pending reminders.")).await?
+                }
+            }
+        }
+        Command::CancelReminder(index) => {
+            let mut reminders = state.reminders.lock().await;
+            let pending = reminders.entry(msg.chat.id).or_default();
+            let reply = match index.trim().parse::<usize>() {
+                Ok(i) if i >= 1 && i <= pending.len() => {
+                    let reminder = pending.remove(i - 1);
+                    reminder.task.abort();
+                    log::info!("Cancelled reminder '{}' of {}", reminder.text, msg.chat.id);
+                    format!("Cancelled the reminder '{}'.", reminder.text)
+                }
+                Ok(_) if pending.is_empty() => "No pending reminders.".to_string(),
+                Ok(_) => format!(
+                    "There is no reminder with that number. Pick one between 1 and {}.",
+                    pending.len()
+                ),
+                Err(_) => "Usage: /cancelreminder <reminder number>".to_string(),
+            };
+            drop(reminders);
+            send_retrying(bot.send_message(msg.chat.id, reply)).await?
+        }
+        Command::Alarm(input) => match parse_alarm(&input) {
+            Ok((time, text)) => {
+                log::info!("Adding alarm '{}' at {} for {}", text, time, msg.chat.id);
+                let mut alarms = state.alarms.lock().await;
+                let pending = alarms.entry(msg.chat.id).or_default();
+                pending.push(Alarm { time, text });
+                pending.sort_by_key(|alarm| alarm.time);
+                save_alarms(&state, msg.chat.id, pending);
+                drop(alarms);
+                send_retrying(bot.send_message(
+                    msg.chat.id,
+                    format!("I'll remind you every day at {}.", time.format("%H:%M")),
+                ))
+                .await?
+            }
+            Err(err) => send_retrying(bot.send_message(msg.chat.id, err)).await?,
+        },
+        Command::Alarms => {
+            let content = state
+                .alarms
+                .lock()
+                .await
+                .get(&msg.chat.id)
+                .filter(|alarms| !alarms.is_empty())
+                .map(|alarms| {
+                    let mut content = "Daily alarms:".to_string();
+                    for (i, alarm) in alarms.iter().enumerate() {
+                        content.push_str(&format!(
+                            "\n{}. {} at {}",
+                            i + 1,
+                            alarm.text,
+                            alarm.time.format("%H:%M")
+                        ));
+                    }
+                    content
+                });
+            match content {
+                Some(content) => send_long(&bot, msg.chat.id, &content, None).await?,
+                None => send_retrying(bot.send_message(msg.chat.id, "No alarms set.")).await?,
+            }
+        }
+        Command::Countdown(input) => {
+            let today = user_today(&state, msg.chat.id).await;
+            let reply = match parse_countdown(&input, today) {
+                Ok(countdown) => {
+                    let mut countdowns = state.countdowns.lock().await;
+                    let countdowns = countdowns.entry(msg.chat.id).or_default();
+                    if countdowns.len() >= MAX_COUNTDOWNS {
+                        format!("You can keep at most {} countdowns.", MAX_COUNTDOWNS)
+                    } else {
+                        log::info!(
+                            "Adding countdown '{}' on {} for {}",
+                            countdown.name,
+                            countdown.date,
+                            msg.chat.id
+                        );
+                        let reply = format_countdown(&countdown, today);
+                        countdowns.push(countdown);
+                        countdowns.sort_by_key(|countdown| countdown.date);
+                        save_countdowns(&state, msg.chat.id, countdowns);
+                        reply
+                    }
+                }
+                Err(err) => err.to_string(),
+            };
+            send_retrying(bot.send_message(msg.chat.id, reply)).await?
+        }
+        Command::Countdowns => {
+            let today = user_today(&state, msg.chat.id).await;
+            let content = state
+                .countdowns
+                .lock()
+                .await
+                .get(&msg.chat.id)
+                .filter(|countdowns| !countdowns.is_empty())
+                .map(|countdowns| {
+                    let mut content = "Countdowns:".to_string();
+                    for countdown in countdowns {
+                        content.push_str(&format!("\n{}", format_countdown(countdown, today)));
+                    }
+                    content
+                });
+            match content {
+                Some(content) => send_long(&bot, msg.chat.id, &content, None).await?,
+                None => send_retrying(bot.send_message(msg.chat.id, "No countdowns yet.")).await?,
+            }
+        }
+        Command::DeleteAlarm(index) => {
+            let mut alarms = state.alarms.lock().await;
+            let pending = alarms.entry(msg.chat.id).or_default();
+            let reply = match index.trim().parse::<usize>() {
+                Ok(i) if i >= 1 && i <= pending.len() => {
+                    let alarm = pending.remove(i - 1);
+                    log::info!("Deleted alarm '{}' of {}", alarm.text, msg.chat.id);
+                    save_alarms(&state, msg.chat.id, pending);
+                    format!("Deleted the alarm '{}'.", alarm.text)
+                }
+                Ok(_) if pending.is_empty() => "No alarms set.".to_string(),
+                Ok(_) => format!(
+                    "There is no alarm with that number. Pick one between 1 and {}.",
+                    pending.len()
+                ),
+                Err(_) => "Usage: /deletealarm <alarm number>".to_string(),
+            };
+            drop(alarms);
+            send_retrying(bot.send_message(msg.chat.id, reply)).await?
+        }
+        Command::RemindAll(_) if config.admin_chat_id != Some(msg.chat.id) => {
+            send_retrying(bot.send_message(msg.chat.id, "Not authorized.")).await?
+        }
+        Command::RemindAll(input) => match parse_reminder(&input, REMIND_ALL_USAGE) {
+            Ok((delay, text)) => {
+                log::info!("Broadcasting in {:?}: '{}'", delay, text);
+                let broadcast_bot = bot.clone();
+                let broadcast_state = state.clone();
+                tokio::spawn(async move {
+                    sleep(delay).await;
+                    log::info!("Broadcasting '{}'", text);
+                    let summary = send_to_all(&broadcast_bot, &broadcast_state, &text).await;
+                    log::info!(
+                        "Scheduled broadcast sent to {} users, {} failed",
+                        summary.sent,
+                        summary.failed
+                    );
+                });
+                let fire_at = Utc::now() + chrono::Duration::from_std(delay).unwrap();
+                let timezone = state.timezones.lock().await.get(&msg.chat.id).copied();
+                send_retrying(bot.send_message(
+                    msg.chat.id,
+                    format!(
+                        "Broadcast scheduled for {}. Note that it is lost if the bot restarts.",
+                        format_user_time(fire_at, timezone)
+                    ),
+                ))
+                .await?
+            }
+            Err(err) => send_retrying(bot.send_message(msg.chat.id, err)).await?,
+        },
+        Command::Broadcast(text) => {
+            if config.admin_chat_id != Some(msg.chat.id) {
+                send_retrying(bot.send_message(msg.chat.id, "Not authorized.")).await?
+            } else if text.trim().is_empty() {
+                send_retrying(bot.send_message(msg.chat.id, "Usage: /broadcast <message>")).await?
+            } else {
+                log::info!("Broadcasting '{}'", text);
+                let summary = send_to_all(&bot, &state, text.trim()).await;
+                send_retrying(bot.send_message(
+                    msg.chat.id,
+                    format!(
+                        "Broadcast sent to {} users, {} failed.",
+                        summary.sent, summary.failed
+                    ),
+                ))
+                .await?
+            }
+        }
+        Command::Feedback(text) if text.trim().is_empty() => {
+            send_retrying(bot.send_message(msg.chat.id, "Usage: /feedback <message>")).await?
+        }
+        Command::Feedback(text) => {
+            let now = Instant::now();
+            let too_soon = {
+                let mut last_feedback = state.last_feedback.lock().await;
+                let too_soon = last_feedback
+                    .get(&msg.chat.id)
+                    .is_some_and(|last| now.duration_since(*last) < FEEDBACK_INTERVAL);
+                if !too_soon {
+                    last_feedback.insert(msg.chat.id, now);
+                }
+                too_soon
+            };
+            if too_soon {
+                send_retrying(bot.send_message(msg.chat.id, "You can send feedback once a minute."))
+                    .await?
+            } else {
+                let feedback = format!(
+                    "Feedback from {} ({}):\n{}",
+                    msg.chat.id,
+                    sender_label(msg.from()),
+                    text.trim()
+                );
+                match config.admin_chat_id {
+                    Some(admin) => {
+                        if let Err(err) =
+                            send_retrying(bot.send_message(admin, truncate_message(&feedback)))
+                                .await
+                        {
+                            log::error!("Failed to forward feedback: {}. {}", err, feedback);
+                        }
+                    }
+                    None => log::info!("{}", feedback),
+                }
+                send_retrying(bot.send_message(msg.chat.id, "Thanks for your feedback!")).await?
+            }
+        }
+        Command::Ping => {
+            let start = Instant::now();
+            let pong = send_retrying(bot.send_message(msg.chat.id, "pong")).await?;
+            let elapsed = start.elapsed().as_millis();
+            match bot
+                .edit_message_text(msg.chat.id, pong.id, format!("pong ({} ms)", elapsed))
+                .await
+            {
+                Ok(edited) => edited,
+                Err(err) => {
+                    log::warn!("Failed to edit ping reply: {}", err);
+                    pong
+                }
+            }
+        }
+        Command::WhoAmI => {
+            let subscribed = state.users_list.lock().await.contains(&msg.chat.id);
+            let todos = state
+                .todo_list
+                .lock()
+                .await
+                .get(&todo_key)
+                .map_or(0, Vec::len);
+            let (username, first_name) = match msg.from() {
+                Some(user) => (
+                    user.username
+                        .as_deref()
+                        .map_or("none".to_string(), |name| format!("@{}", name)),
+                    user.first_name.clone(),
+                ),
+                None => ("none".to_string(), "unknown".to_string()),
+            };
+            send_retrying(bot.send_message(
+                msg.chat.id,
+                format!(
+                    "<u>Who am I:</u>\n<b>Chat id:</b> <code>{}</code>\n<b>Username:</b> {}\n<b>First name:</b> {}\n<b>Subscribed:</b> {}\n<b>Todo items:</b> {}",
+                    msg.chat.id,
+                    escape(&username),
+                    escape(&first_name),
+                    if subscribed { "yes" } else { "no" },
+                    todos
+                ),
+            )
+            .parse_mode(ParseMode::Html))
+            .await?
+        }
+        Command::Stats => {
+            let users = state.users_list.lock().await.len();
+            let todos: usize = state.todo_list.lock().await.values().map(Vec::len).sum();
+            let mut content = format!(
+                "<u>Stats:</u>\n<b>Subscribed users:</b> {}\n<b>Todo items:</b> {}\n<b>Uptime:</b> {}",
+                users,
+                todos,
+                format_uptime(state.start_time.elapsed())
+            );
+            if config.admin_chat_id == Some(msg.chat.id) {
+                content.push_str(&user_breakdown(&state).await);
+            }
+            send_long(&bot, msg.chat.id, &content, Some(ParseMode::Html)).await?
+        }
+        Command::ListAll if config.admin_chat_id != Some(msg.chat.id) => {
+            send_retrying(bot.send_message(msg.chat.id, "Not authorized.")).await?
+        }
+        Command::ListAll => {
+            let content = render_all_todos(&*state.todo_list.lock().await);
+            send_long(&bot, msg.chat.id, &content, Some(ParseMode::Html)).await?
+        }
+        Command::Metrics => {
+            let mut counts: Vec<(String, u64)> = state
+                .command_counts
+                .lock()
+                .await
+                .iter()
+                .map(|(name, count)| (name.clone(), *count))
+                .collect();
+            counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+            let mut content = "<u>Command usage:</u>\n".to_string();
+            for (name, count) in &counts {
+                content.push_str(&format!("/{}: {}\n", name, count));
+            }
+            content.push_str(&format!(
+                "<b>Total:</b> {}",
+                counts.iter().map(|(_, count)| count).sum::<u64>()
+            ));
+            send_retrying(
+                bot.send_message(msg.chat.id, content)
+                    .parse_mode(ParseMode::Html),
+            )
+            .await?
+        }
+        Command::Echo(text) if text.trim().is_empty() => {
+            send_retrying(bot.send_message(msg.chat.id, "(nothing to echo)")).await?
+        }
+        Command::Echo(text) => send_retrying(bot.send_message(msg.chat.id, text)).await?,
+        Command::Subscribe => {
+            log::info!("Subscribing {}", msg.chat.id);
+            state.unsubscribed_list.lock().await.remove(&msg.chat.id);
+            state.users_list.lock().await.insert(msg.chat.id);
+            save_user(&state, msg.chat.id, true);
+            send_retrying(bot.send_message(msg.chat.id, "You will now receive daily messages."))
+                .await?
+        }
+        Command::Unsubscribe => {
+            log::info!("Unsubscribing {}", msg.chat.id);
+            state.users_list.lock().await.remove(&msg.chat.id);
+            state.unsubscribed_list.lock().await.insert(msg.chat.id);
+            save_user(&state, msg.chat.id, false);
+            send_retrying(bot.send_message(
+                msg.chat.id,
+                "You won't receive daily messages anymore. Use /subscribe to opt back in.",
+            ))
+            .await?
+        }
+    };
+
+    Ok(())
+}
+
+const DEFINITIONS_PER_MEANING: usize = 2;
+
+/// Lists the first few definitions of every meaning in a dictionary API
+/// response, labelled with their part of speech. Returns `None` for the API's
+/// error object, which carries a `title` field.
+fn format_definitions(json: &Value) -> Option<String> {
+    if json.get("title").is_some() {
+        return None;
+    }
+    let mut content = String::new();
+    for meaning in json[0]["meanings"].as_array()? {
+        let part_of_speech = meaning["partOfSpeech"].as_str().unwrap_or("other");
+        let definitions = meaning["definitions"].as_array().into_iter().flatten();
+        for definition in definitions
+            .filter_map(|definition| definition["definition"].as_str())
+            .take(DEFINITIONS_PER_MEANING)
+        {
+            content.push_str(&format!("{}: {}\n", part_of_speech, definition));
+        }
+    }
+    if content.is_empty() {
+        None
+    } else {
+        Some(content)
+    }
+}
+
+/// First pronunciation recording in a dictionary API response, if any.
+fn pronunciation_audio(json: &Value) -> Option<Url> {
+    json.as_array()?
+        .iter()
+        .flat_map(|entry| entry["phonetics"].as_array().into_iter().flatten())
+        .filter_map(|phonetic| phonetic["audio"].as_str())
+        .find(|audio| !audio.trim().is_empty())
+        .and_then(|audio| Url::parse(audio.trim()).ok())
+}
+
+const MAX_REMINDER_DELAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Parses durations like `45s`, `30m` or `2h`.
+fn parse_duration(input: &str) -> Option<Duration> {
+    let unit = input.chars().last()?;
+    let amount = input[..input.len() - unit.len_utf8()].parse::<u64>().ok()?;
+    let seconds = match unit {
+        's' => amount,
+        'm' => amount.checked_mul(60)?,
+        'h' => amount.checked_mul(60 * 60)?,
+        _ => return None,
+    };
+    Some(Duration::from_secs(seconds))
+}
+
+/// Sends `text` to `chat_id` after `delay`, tracking it in the pending
+/// reminders until then. Returns when it will fire.
+async fn schedule_reminder(
+    bot: &Bot,
+    state: &Arc<State>,
+    chat_id: ChatId,
+    delay: Duration,
+    text: String,
+) -> DateTime<Utc> {
+    let fire_at = Utc::now() + chrono::Duration::from_std(delay).unwrap();
+    let id = state.next_reminder_id.fetch_add(1, Ordering::Relaxed);
+    let reminder_bot = bot.clone();
+    let reminder_state = state.clone();
+    let reminder_text = text.clone();
+    // Hold the lock while spawning so the task can't fire and try to remove
+    // its entry before it has been added.
+    let mut reminders = state.reminders.lock().await;
+    let task = tokio::spawn(async move {
+        sleep(delay).await;
+        if let Some(pending) = reminder_state.reminders.lock().await.get_mut(&chat_id) {
+            pending.retain(|reminder| reminder.id != id);
+        }
+        reminder_state
+            .last_fired_reminder
+            .lock()
+            .await
+            .insert(chat_id, reminder_text.clone());
+        let reminder = format!(
+            "⏰ Reminder: {}\n\nUse /snooze 10m to be reminded again.",
+            reminder_text
+        );
+        if let Err(err) = send_retrying(reminder_bot.send_message(chat_id, reminder)).await {
+            log::error!("Failed to send reminder to {}: {}", chat_id, err);
+        }
+    });
+    let pending = reminders.entry(chat_id).or_default();
+    pending.push(Reminder {
+        id,
+        fire_at,
+        text,
+        task: task.abort_handle(),
+    });
+    pending.sort_by_key(|reminder| reminder.fire_at);
+    fire_at
+}
+
+const REMIND_USAGE: &str = "Usage: /remind <duration> <message>, e.g. /remind 30m buy milk. \
+    Durations take an s, m or h suffix.";
+const REMIND_ALL_USAGE: &str = "Usage: /remindall <duration> <message>, \
+    e.g. /remindall 1h the event starts now. Durations take an s, m or h suffix.";
+
+/// Splits `/remind` input into the delay and the reminder text, replying with
+/// `usage` when it's malformed.
+fn parse_reminder(input: &str, usage: &'static str) -> Result<(Duration, String), &'static str> {
+    let input = input.trim();
+    let (delay, text) = input.split_once(char::is_whitespace).ok_or(usage)?;
+    let delay = parse_duration(delay).ok_or(usage)?;
+    if delay > MAX_REMINDER_DELAY {
+        return Err("Reminders can be at most 24h away.");
+    }
+    let text = text.trim();
+    if text.is_empty() {
+        return Err(usage);
+    }
+    Ok((delay, text.to_string()))
+}
+
+const ALARM_USAGE: &str = "Usage: /alarm <HH:MM> <message>, e.g. /alarm 07:30 take meds";
+
+/// Splits `/alarm` input into the daily time and the alarm text.
+fn parse_alarm(input: &str) -> Result<(NaiveTime, String), &'static str> {
+    let (time, text) = input
+        .trim()
+        .split_once(char::is_whitespace)
+        .ok_or(ALARM_USAGE)?;
+    let time = NaiveTime::parse_from_str(time, "%H:%M").map_err(|_| ALARM_USAGE)?;
+    let text = text.trim();
+    if text.is_empty() {
+        return Err(ALARM_USAGE);
+    }
+    Ok((time, text.to_string()))
+}
+
+const REMINDME_USAGE: &str = "Usage: /remindme <time> <message>, \
+    e.g. /remindme tomorrow 9am call dentist or /remindme 30m buy milk";
+const MAX_REMINDME_DELAY: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+/// Most words a /remindme time phrase can take, as in "next friday 17:00".
+const MAX_TIME_PHRASE_WORDS: usize = 4;
+/// When a /remindme phrase that only names a day, like "monday", goes off.
+const DEFAULT_REMINDME_TIME: NaiveTime = NaiveTime::from_hms_opt(9, 0, 0).unwrap();
+
+/// Splits `/remindme` input into the delay until its leading natural time
+/// phrase and the reminder text, falling back to `/remind`'s durations.
+fn parse_remindme(input: &str, timezone: Option<Tz>) -> Result<(Duration, String), &'static str> {
+    let words: Vec<&str> = input.split_whitespace().collect();
+    let parsed = match timezone {
+        Some(timezone) => natural_time(&words, Utc::now().with_timezone(&timezone)),
+        None => natural_time(&words, Local::now()),
+    };
+    let Some((phrase_len, fire_at)) = parsed else {
+        return parse_reminder(input, REMINDME_USAGE);
+    };
+    let text = words[phrase_len..].join(" ");
+    if text.is_empty() {
+        return Err(REMINDME_USAGE);
+    }
+    let delay = (fire_at - Utc::now())
+        .to_std()
+        .map_err(|_| "That time is in the past.")?;
+    if delay > MAX_REMINDME_DELAY {
+        return Err("Reminders can be at most a week away.");
+    }
+    Ok((delay, text))
+}
+
+/// Finds the natural time phrase at the start of `words`. The parser ignores
+/// trailing words, so the phrase ends once another word stops changing the
+/// parsed time. Phrases without a time of day go off at
+/// DEFAULT_REMINDME_TIME.
+fn natural_time<T: TimeZone>(words: &[&str], now: DateTime<T>) -> Option<(usize, DateTime<Utc>)>
+where
+    T::Offset: Copy,
+{
+    let mut found: Option<(usize, DateTime<T>)> = None;
+    for len in 1..=words.len().min(MAX_TIME_PHRASE_WORDS) {
+        let Ok(time) = parse_date_string(&words[..len].join(" "), now, Dialect::Uk) else {
+            continue;
+        };
+        // Bare numbers parse as years, like the 2 of "2 apples".
+        if time < now - chrono::Duration::days(1) {
+            continue;
+        }
+        if found.is_none_or(|(_, previous)| previous != time) {
+            found = Some((len, time));
+        }
+    }
+    let (len, mut time) = found?;
+    let names_time_of_day = words[..len].iter().any(|word| {
+        let word = word.to_ascii_lowercase();
+        word.contains(':') || word.ends_with("am") || word.ends_with("pm")
+    });
+    if time.time() == NaiveTime::MIN && !names_time_of_day {
+        let local = time.date_naive().and_time(DEFAULT_REMINDME_TIME);
+        time = now.timezone().from_local_datetime(&local).earliest()?;
+    }
+    Some((len, time.with_timezone(&Utc)))
+}
+
+const COUNTDOWN_USAGE: &str =
+    "Usage: /countdown <YYYY-MM-DD> <event>, e.g. /countdown 2030-01-01 New Year";
+const MAX_COUNTDOWNS: usize = 20;
+
+/// Parses `/countdown` input, rejecting dates before `today`.
+fn parse_countdown(input: &str, today: NaiveDate) -> Result<Countdown, &'static str> {
+    let (date, name) = input
+        .trim()
+        .split_once(char::is_whitespace)
+        .ok_or(COUNTDOWN_USAGE)?;
+    let date = NaiveDate::parse_from_str(date, "%Y-%m-%d").map_err(|_| COUNTDOWN_USAGE)?;
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(COUNTDOWN_USAGE);
+    }
+    if date < today {
+        return Err("That date has already passed.");
+    }
+    Ok(Countdown {
+        date,
+        name: name.to_string(),
+    })
+}
+
+/// Describes how far `countdown` is from `today`.
+fn format_countdown(countdown: &Countdown, today: NaiveDate) -> String {
+    let when = match (countdown.date - today).num_days() {
+        ..=-1 => "passed".to_string(),
+        0 => "today!".to_string(),
+        1 => "1 day left".to_string(),
+        days => format!("{} days left", days),
+    };
+    format!("{} ({}): {}", countdown.name, countdown.date, when)
+}
+
+const MESSAGE_LIMIT: usize = 4096;
+
+/// Cuts `text` down to Telegram's message limit, noting when it was truncated.
+fn truncate_message(text: &str) -> String {
+    const NOTE: &str = "\n\n[truncated]";
+    if text.chars().count() <= MESSAGE_LIMIT {
+        return text.to_string();
+    }
+    let mut truncated: String = text
+        .chars()
+        .take(MESSAGE_LIMIT - NOTE.chars().count())
+        .collect();
+    truncated.push_str(NOTE);
+    truncated
+}
+
+/// Formats the next three days of a wttr.in `format=j1` response.
+fn format_forecast(json: &Value) -> Option<String> {
+    let mut content = String::new();
+    for day in json["weather"].as_array()?.iter().take(3) {
+        // wttr.in reports eight 3-hourly entries per day, the fifth one is noon
+        let hourly = day["hourly"].as_array()?;
+        let condition = hourly
+            .get(4)
+            .or_else(|| hourly.first())
+            .and_then(|hour| hour["weatherDesc"][0]["value"].as_str())
+            .unwrap_or("Unknown");
+        content.push_str(&format!(
+            "{}: {} to {}°C, {}\n",
+            day["date"].as_str()?,
+            day["mintempC"].as_str()?,
+            day["maxtempC"].as_str()?,
+            condition.trim()
+        ));
+    }
+    if content.is_empty() {
+        None
+    } else {
+        Some(content)
+    }
+}
+
+fn format_uptime(uptime: Duration) -> String {
+    let minutes = uptime.as_secs() / 60;
+    format!(
+        "{}d {}h {}m",
+        minutes / (24 * 60),
+        minutes / 60 % 24,
+        minutes % 60
+    )
+}
+
+/// Commands that wait on an external API before replying.
+fn is_network_bound(cmd: &Command) -> bool {
+    matches!(
+        cmd,
+        Command::Cat(_)
+            | Command::Cats(_)
+            | Command::Dog
+            | Command::Define(_)
+            | Command::Useless
+            | Command::Joke
+            | Command::Quote
+            | Command::News
+            | Command::Summarize(_)
+            | Command::Holidays(_)
+            | Command::Crypto(_)
+            | Command::Translate(_)
+            | Command::Raw(_)
+            | Command::Weather(_)
+            | Command::Forecast(_)
+    )
+}
+
+/// Shows "typing…" while a slow reply is prepared.
+async fn send_typing(bot: &Bot, chat_id: ChatId) {
+    if let Err(err) = bot.send_chat_action(chat_id, ChatAction::Typing).await {
+        log::warn!("Failed to send typing action to {}: {}", chat_id, err);
+    }
+}
+
+/// Who sent an update, for log lines.
+fn sender_label(user: Option<&teloxide::types::User>) -> String {
+    match user {
+        Some(user) => match &user.username {
+            Some(username) => format!("@{}", username),
+            None => user.full_name(),
+        },
+        None => "unknown sender".to_string(),
+    }
+}
+
+/// Per subscriber lines for the admin's /stats, most active first.
+async fn user_breakdown(state: &State) -> String {
+    let users: Vec<ChatId> = state.users_list.lock().await.iter().copied().collect();
+    let mut todo_counts: HashMap<ChatId, usize> = HashMap::new();
+    for (key, tasks) in state.todo_list.lock().await.iter() {
+        *todo_counts.entry(key.chat).or_default() += tasks.len();
+    }
+    let chat_activity = state.chat_activity.lock().await;
+    let mut rows: Vec<(ChatId, Option<&ChatActivity>)> = users
+        .into_iter()
+        .map(|user| (user, chat_activity.get(&user)))
+        .collect();
+    rows.sort_by_key(|(user, activity)| {
+        (
+            std::cmp::Reverse(activity.map_or(0, |activity| activity.commands)),
+            user.0,
+        )
+    });
+    let mut content = "\n\n<u>Users:</u>".to_string();
+    for (user, activity) in rows {
+        content.push_str(&format!(
+            "\n{} {}: {} commands, {} todos",
+            user,
+            escape(
+                activity
+                    .and_then(|activity| activity.name.as_deref())
+                    .unwrap_or("unknown")
+            ),
+            activity.map_or(0, |activity| activity.commands),
+            todo_counts.get(&user).copied().unwrap_or(0)
+        ));
+    }
+    content
+}
+
+const FEEDBACK_INTERVAL: Duration = Duration::from_secs(60);
+
+const MAX_NICKNAME_LEN: usize = 32;
+
+/// The /setname nickname, else the sender's username, first name or "there".
+fn greeting_name(msg: &Message, nickname: Option<&str>) -> String {
+    if let Some(nickname) = nickname {
+        return nickname.to_string();
+    }
+    let Some(user) = msg.from() else {
+        return "there".to_string();
+    };
+    match &user.username {
+        Some(username) => username.clone(),
+        None if !user.first_name.is_empty() => user.first_name.clone(),
+        None => "there".to_string(),
+    }
+}
+
+const FETCH_ATTEMPTS: u32 = 3;
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(200);
+
+/// GETs `url`, retrying connection failures, timeouts and 5xx responses with
+/// exponential backoff. The last attempt's result is returned as is.
+async fn fetch_with_retry(
+    http: &reqwest::Client,
+    url: &str,
+    attempts: u32,
+) -> reqwest::Result<reqwest::Response> {
+    let mut delay = RETRY_BASE_DELAY;
+    let mut attempt = 1;
+    loop {
+        let result = http.get(url).send().await;
+        let retryable = match &result {
+            Ok(resp) => resp.status().is_server_error(),
+            Err(err) => err.is_connect() || err.is_timeout(),
+        };
+        if !retryable || attempt >= attempts {
+            return result;
+        }
+        log::warn!(
+            "Fetching {} failed (attempt {}/{}), retrying in {:?}",
+            url,
+            attempt,
+            attempts,
+            delay
+        );
+        sleep(delay).await;
+        delay *= 2;
+        attempt += 1;
+    }
+}
+
+const HEADLINES: usize = 5;
+
+/// Returns the title and link of the top headlines.
+async fn get_headlines(
+    http: &reqwest::Client,
+    config: &Config,
+    api_key: &str,
+) -> Result<Vec<(String, String)>, BoxError> {
+    let resp = http
+        .get(&config.news_api_url)
+        .query(&[("country", "us"), ("pageSize", &HEADLINES.to_string())])
+        .header("X-Api-Key", api_key)
+        .send()
+        .await?;
+    let status = resp.status();
+    let json: Value = resp.json().await?;
+    if !status.is_success() || json["status"] == "error" {
+        let message = json["message"].as_str().unwrap_or("unknown error");
+        return Err(format!("news service responded with {}: {}", status, message).into());
+    }
+    let articles = json["articles"]
+        .as_array()
+        .ok_or("news response has no articles")?;
+    Ok(articles
+        .iter()
+        .filter_map(|article| {
+            Some((
+                article["title"].as_str()?.to_string(),
+                article["url"].as_str()?.to_string(),
+            ))
+        })
+        .take(HEADLINES)
+        .collect())
+}
+
+const HOLIDAYS: usize = 5;
+
+/// Returns the dates and names of the next HOLIDAYS public holidays in
+/// `country`, or None when Nager.Date doesn't know the country.
+async fn get_holidays(
+    http: &reqwest::Client,
+    config: &Config,
+    country: &str,
+) -> Result<Option<Vec<(NaiveDate, String)>>, BoxError> {
+    let url = format!("{}/{}", config.holidays_api_url, country);
+    let resp = fetch_with_retry(http, &url, FETCH_ATTEMPTS).await?;
+    match resp.status() {
+        reqwest::StatusCode::NOT_FOUND | reqwest::StatusCode::NO_CONTENT => return Ok(None),
+        status if !status.is_success() => {
+            return Err(format!("holiday service responded with {}", status).into())
+        }
+        _ => {}
+    }
+    let json: Value = resp.json().await?;
+    let holidays = json.as_array().ok_or("holidays response is not an array")?;
+    Ok(Some(
+        holidays
+            .iter()
+            .filter_map(|holiday| {
+                let date = NaiveDate::parse_from_str(holiday["date"].as_str()?, "%Y-%m-%d").ok()?;
+                let name = holiday["name"].as_str()?;
+                Some((date, name.to_string()))
+            })
+            .take(HOLIDAYS)
+            .collect(),
+    ))
+}
+
+/// Returns the content and author of a random quote.
+async fn get_quote(http: &reqwest::Client, config: &Config) -> Result<(String, String), BoxError> {
+    let resp = fetch_with_retry(http, &config.quote_api_url, FETCH_ATTEMPTS).await?;
+    let json: Value = resp.json().await?;
+    let content = json["content"].as_str().ok_or("quote has no content")?;
+    let author = json["author"].as_str().unwrap_or("Unknown");
+    Ok((content.to_string(), author.to_string()))
+}
+
+/// Returns the setup and punchline of a random joke.
+async fn get_joke(http: &reqwest::Client, config: &Config) -> Result<(String, String), BoxError> {
+    let resp = http.get(&config.joke_api_url).send().await?;
+    let json: Value = resp.json().await?;
+    let setup = json["setup"].as_str().ok_or("joke has no setup")?;
+    let punchline = json["punchline"].as_str().ok_or("joke has no punchline")?;
+    Ok((setup.to_string(), punchline.to_string()))
+}
+
+/// Tickers accepted by /crypto and their CoinGecko ids.
+const COINS: &[(&str, &str)] = &[
+    ("btc", "bitcoin"),
+    ("eth", "ethereum"),
+    ("usdt", "tether"),
+    ("bnb", "binancecoin"),
+    ("sol", "solana"),
+    ("xrp", "ripple"),
+    ("ada", "cardano"),
+    ("doge", "dogecoin"),
+    ("ltc", "litecoin"),
+    ("dot", "polkadot"),
+];
+
+fn coin_id(ticker: &str) -> Option<&'static str> {
+    let ticker = ticker.trim().to_lowercase();
+    COINS
+        .iter()
+        .find(|(known, id)| *known == ticker || *id == ticker)
+        .map(|(_, id)| *id)
+}
+
+async fn get_coin_price(
+    http: &reqwest::Client,
+    config: &Config,
+    id: &str,
+) -> Result<f64, BoxError> {
+    let url = format!("{}?ids={}&vs_currencies=usd", config.crypto_api_url, id);
+    let json: Value = http.get(url).send().await?.json().await?;
+    Ok(json[id]["usd"]
+        .as_f64()
+        .ok_or("unexpected response from the price service")?)
+}
+
+/// Language codes served by the dictionary API.
+const DICTIONARY_LANGUAGES: &[&str] = &[
+    "en", "hi", "es", "fr", "ja", "ru", "de", "it", "ko", "pt-BR", "ar", "tr",
+];
+
+const USELESS_FACTS_LANGUAGES: &[&str] = &["en", "de"];
+
+/// Matches a /setlang preference against the codes an API supports.
+fn supported_language(preferred: Option<&str>, supported: &[&'static str]) -> Option<&'static str> {
+    let preferred = preferred?;
+    supported
+        .iter()
+        .find(|language| language.eq_ignore_ascii_case(preferred))
+        .copied()
+}
+
+/// Splits /define input into a language code and the word, treating the
+/// first token as the language only when it's a supported code and falling
+/// back to `default` otherwise.
+fn parse_define<'a>(input: &'a str, default: &'static str) -> (&'static str, &'a str) {
+    let input = input.trim();
+    if let Some((first, rest)) = input.split_once(char::is_whitespace) {
+        if let Some(language) = DICTIONARY_LANGUAGES
+            .iter()
+            .find(|language| language.eq_ignore_ascii_case(first))
+        {
+            return (language, rest.trim());
+        }
+    }
+    (default, input)
+}
+
+/// Formats a price as dollars with thousands separators, keeping more
+/// decimals for coins worth less than a dollar.
+fn format_usd(price: f64) -> String {
+    let decimals = if price < 1.0 { 6 } else { 2 };
+    let formatted = format!("{:.*}", decimals, price);
+    let (whole, fraction) = formatted.split_once('.').unwrap_or((&formatted, ""));
+    let mut grouped = String::new();
+    for (i, digit) in whole.chars().enumerate() {
+        if i > 0 && (whole.len() - i) % 3 == 0 {
+            grouped.push(',');
+        }
+        grouped.push(digit);
+    }
+    format!("${}.{}", grouped, fraction)
+}
+
+async fn translate(
+    http: &reqwest::Client,
+    config: &Config,
+    target: &str,
+    text: &str,
+) -> Result<String, BoxError> {
+    let mut body = serde_json::json!({
+        "q": text,
+        "source": "auto",
+        "target": target,
+        "format": "text",
+    });
+    if let Some(api_key) = &config.translate_api_key {
+        body["api_key"] = Value::String(api_key.clone());
+    }
+    let resp = http.post(&config.translate_url).json(&body).send().await?;
+    let json: Value = resp.json().await?;
+    if let Some(error) = json["error"].as_str() {
+        return Err(error.into());
+    }
+    Ok(json["translatedText"]
+        .as_str()
+        .ok_or("unexpected response from the translation service")?
+        .to_string())
+}
+
+/// Longest text sent for summarizing, to stay well inside the model's
+/// context window.
+const MAX_SUMMARY_INPUT: usize = 12_000;
+
+/// Asks the OpenAI compatible chat completions endpoint for a short summary
+/// of `text`, which is cut to MAX_SUMMARY_INPUT characters first.
+async fn summarize(
+    http: &reqwest::Client,
+    config: &Config,
+    text: &str,
+) -> Result<String, BoxError> {
+    let api_key = config
+        .summary_api_key
+        .as_deref()
+        .ok_or("no SUMMARY_API_KEY is set")?;
+    let text: String = text.chars().take(MAX_SUMMARY_INPUT).collect();
+    let body = serde_json::json!({
+        "model": config.summary_model,
+        "messages": [
+            {
+                "role": "system",
+                "content": "Summarize the user's text in a few short sentences.",
+            },
+            { "role": "user", "content": text },
+        ],
+    });
+    let resp = http
+        .post(&config.summary_api_url)
+        .bearer_auth(api_key)
+        .timeout(config.summary_timeout)
+        .json(&body)
+        .send()
+        .await?;
+    let status = resp.status();
+    let json: Value = resp.json().await?;
+    if !status.is_success() {
+        let message = json["error"]["message"].as_str().unwrap_or("unknown error");
+        return Err(format!("summary service responded with {}: {}", status, message).into());
+    }
+    Ok(json["choices"][0]["message"]["content"]
+        .as_str()
+        .ok_or("unexpected response from the summary service")?
+        .trim()
+        .to_string())
+}
+
+/// Sends the fetched image, or tells the user that fetching the `what` failed.
+async fn send_image(
+    bot: &Bot,
+    chat_id: ChatId,
+    image: Result<Url, BoxError>,
+    what: &str,
+) -> ResponseResult<Message> {
+    match image {
+        Ok(url) => send_retrying(bot.send_photo(chat_id, InputFile::url(url))).await,
+        Err(err) => {
+            log::error!("Failed to fetch {}: {}", what, err);
+            send_retrying(bot.send_message(chat_id, format!("Failed to fetch {}.", what))).await
+        }
+    }
+}
+
+async fn get_dog_image(http: &reqwest::Client, config: &Config) -> Result<Url, BoxError> {
+    let resp = http.get(&config.dog_api_url).send().await?;
+    let json: Value = resp.json().await?;
+    if json["status"] != "success" {
+        return Err(format!("Dog API responded with status {}", json["status"]).into());
+    }
+    let url = json["message"]
+        .as_str()
+        .ok_or("Dog API response has no message field")?;
+    Ok(Url::parse(url)?)
+}
+
+const MAX_CATS: usize = 5;
+
+/// Fetches up to `limit` cat image urls, always at least one, optionally
+/// only of the breed with `breed_id`.
+async fn get_cat_images(
+    http: &reqwest::Client,
+    config: &Config,
+    limit: usize,
+    breed_id: Option<&str>,
+) -> Result<Vec<Url>, BoxError> {
+    let mut url = format!("{}?limit={}", config.cat_api_url, limit);
+    if let Some(breed_id) = breed_id {
+        url.push_str(&format!("&breed_ids={}", urlencoding::encode(breed_id)));
+    }
+    let resp = fetch_with_retry(http, &url, FETCH_ATTEMPTS).await?;
+    let mut images = parse_cat_images(&resp.text().await?)?;
+    images.truncate(limit);
+    Ok(images)
+}
+
+const RECENT_CATS: usize = 10;
+const CAT_FETCH_ATTEMPTS: u32 = 3;
+
+/// Fetches a cat image `chat_id` hasn't seen recently, settling for a repeat
+/// after CAT_FETCH_ATTEMPTS tries.
+async fn get_fresh_cat_image(
+    state: &State,
+    config: &Config,
+    chat_id: ChatId,
+    breed_id: Option<&str>,
+) -> Result<Url, BoxError> {
+    let mut attempt = 1;
+    let url = loop {
+        let url = get_cat_images(&state.http, config, 1, breed_id)
+            .await?
+            .swap_remove(0);
+        let seen = state
+            .recent_cats
+            .lock()
+            .await
+            .get(&chat_id)
+            .is_some_and(|recent| recent.contains(&url));
+        if !seen || attempt >= CAT_FETCH_ATTEMPTS {
+            break url;
+        }
+        log::info!("{} already got {}, fetching another cat", chat_id, url);
+        attempt += 1;
+    };
+    let mut recent_cats = state.recent_cats.lock().await;
+    let recent = recent_cats.entry(chat_id).or_default();
+    recent.push_back(url.clone());
+    if recent.len() > RECENT_CATS {
+        recent.pop_front();
+    }
+    Ok(url)
+}
+
+/// Maps the lowercase name and id of every Cat API breed to its id.
+async fn get_cat_breeds(
+    http: &reqwest::Client,
+    config: &Config,
+) -> Result<HashMap<String, String>, BoxError> {
+    let resp = fetch_with_retry(http, &config.cat_breeds_url, FETCH_ATTEMPTS).await?;
+    let json: Value = resp.json().await?;
+    let breeds = json
+        .as_array()
+        .ok_or("Cat API breeds response is not an array")?;
+    let mut ids = HashMap::new();
+    for breed in breeds {
+        if let (Some(id), Some(name)) = (breed["id"].as_str(), breed["name"].as_str()) {
+            ids.insert(name.to_lowercase(), id.to_string());
+            ids.insert(id.to_lowercase(), id.to_string());
+        }
+    }
+    Ok(ids)
+}
+
+/// Extracts the image urls from a Cat API search response body.
+fn parse_cat_images(body: &str) -> Result<Vec<Url>, BoxError> {
+    let json: Value = from_str(body)?;
+    let images = json.as_array().ok_or("Cat API response is not an array")?;
+    if images.is_empty() {
+        return Err("Cat API returned no images".into());
+    }
+    images
+        .iter()
+        .map(|image| {
+            let url = image["url"]
+                .as_str()
+                .ok_or("Cat API image has no url field")?;
+            Ok(Url::parse(url)?)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    /// A fresh directory for one test's files.
+    fn temp_dir(test: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("herr-jr-{}-{}", test, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn flip_coin_lands_on_both_sides() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let flips: HashSet<&str> = (0..1000).map(|_| flip_coin(&mut rng, 0.5)).collect();
+        assert!(flips.contains("Heads"));
+        assert!(flips.contains("Tails"));
+    }
+
+    #[test]
+    fn todo_text_is_escaped() {
+        let today = Local::now().date_naive();
+        let tasks = [TodoItem::new("a < b & c".to_string())];
+        assert!(render_todo_list(&tasks, today).contains("a &lt; b &amp; c"));
+
+        let added = ["a < b & c".to_string()];
+        assert!(todo_confirmation(&added, &[]).contains("a &lt; b &amp; c"));
+        let duplicates = [(1, "a < b & c".to_string())];
+        assert!(todo_confirmation(&[], &duplicates).contains("a &lt; b &amp; c"));
+    }
+
+    #[test]
+    fn malformed_cat_api_bodies_are_errors() {
+        assert!(parse_cat_images("not json").is_err());
+        assert!(parse_cat_images(r#"[{"id": "abc", "width": 500}]"#).is_err());
+    }
+
+    #[test]
+    fn dictionary_error_body_has_no_definitions() {
+        let json: Value = from_str(
+            r#"{"title":"No Definitions Found","message":"Sorry pal, we couldn't find definitions for the word you were looking for.","resolution":"You can try the search again at later time or head to the web instead."}"#,
+        )
+        .unwrap();
+        assert_eq!(format_definitions(&json), None);
+    }
+
+    #[test]
+    fn read_chat_ids_skips_blank_and_invalid_lines() {
+        let path = temp_dir("read_chat_ids").join("users.txt");
+        std::fs::write(&path, "1\n\nabc\n 2 \n").unwrap();
+        assert_eq!(read_chat_ids(&path), HashSet::from([ChatId(1), ChatId(2)]));
+    }
+
+    #[test]
+    fn malformed_json_is_backed_up() {
+        let dir = temp_dir("malformed_json");
+        let path = dir.join("todo.json");
+        std::fs::write(&path, "{not json").unwrap();
+        assert!(read_legacy_todos(&path).is_empty());
+        assert!(dir.join("todo.json.bak").exists());
+
+        let path = dir.join("cities.json");
+        std::fs::write(&path, "{not json").unwrap();
+        assert!(read_json::<HashMap<ChatId, String>>(&path).is_empty());
+        assert!(dir.join("cities.json.bak").exists());
+    }
+
+    #[test]
+    fn late_ticks_ring_skipped_alarms() {
+        let chat = ChatId(1);
+        let alarm = |time: &str| Alarm {
+            time: NaiveTime::parse_from_str(time, "%H:%M").unwrap(),
+            text: time.to_string(),
+        };
+        let alarms = HashMap::from([(chat, vec![alarm("07:30"), alarm("07:31"), alarm("08:00")])]);
+        let timezones = HashMap::from([(chat, Tz::UTC)]);
+        let minute = |time: &str| {
+            let time = NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M").unwrap();
+            time.and_utc().timestamp() / 60
+        };
+
+        let ringing = due_alarms(
+            &alarms,
+            &timezones,
+            minute("2024-01-01 07:29")..=minute("2024-01-01 07:31"),
+        );
+        assert_eq!(
+            ringing,
+            vec![(chat, "07:30".to_string()), (chat, "07:31".to_string())]
+        );
+        // A gap of more than a day still rings each alarm only once.
+        let ringing = due_alarms(
+            &alarms,
+            &timezones,
+            minute("2024-01-01 00:00")..=minute("2024-01-03 00:00"),
+        );
+        assert_eq!(ringing.len(), 3);
+    }
+
+    #[test]
+    fn natural_times_default_to_the_morning() {
+        // A Wednesday afternoon.
+        let now = Utc.with_ymd_and_hms(2024, 1, 3, 15, 20, 0).unwrap();
+        let at = |day, hour, minute| Utc.with_ymd_and_hms(2024, 1, day, hour, minute, 0).unwrap();
+        let natural = |input: &str| {
+            let words: Vec<&str> = input.split_whitespace().collect();
+            natural_time(&words, now)
+        };
+
+        assert_eq!(natural("monday standup"), Some((1, at(8, 9, 0))));
+        assert_eq!(natural("friday 5 things"), Some((1, at(5, 9, 0))));
+        assert_eq!(natural("tomorrow 9am call dentist"), Some((2, at(4, 9, 0))));
+        assert_eq!(natural("tomorrow 17:30 gym"), Some((2, at(4, 17, 30))));
+        assert_eq!(natural("tomorrow water plants"), Some((1, at(4, 15, 20))));
+        assert_eq!(natural("2 apples"), None);
+        assert_eq!(natural("buy milk"), None);
+    }
+
+    #[test]
+    fn remindme_falls_back_to_durations() {
+        let (delay, text) = parse_remindme("30m buy milk", None).unwrap();
+        assert_eq!(delay.as_secs().div_ceil(60), 30);
+        assert_eq!(text, "buy milk");
+
+        let (delay, text) = parse_remindme("tomorrow 9am call dentist", None).unwrap();
+        assert!(delay <= Duration::from_secs(2 * 24 * 60 * 60));
+        assert_eq!(text, "call dentist");
+
+        assert_eq!(parse_remindme("2 apples", None), Err(REMINDME_USAGE));
+        assert_eq!(parse_remindme("monday", None), Err(REMINDME_USAGE));
+        assert_eq!(parse_remindme("buy milk", None), Err(REMINDME_USAGE));
+    }
+
+    #[test]
+    fn split_html_chunks_keep_their_tags() {
+        let text = format!("Added <u>{}</u> to todo list", "&lt;".repeat(1100));
+        let chunks = balance_html_chunks(split_message(&text));
+        assert_eq!(chunks.len(), 2);
+        assert!(chunks[0].starts_with("Added <u>&lt;"));
+        assert!(chunks[0].ends_with("&lt;</u>"));
+        assert!(chunks[1].starts_with("<u>&lt;"));
+        assert!(chunks[1].ends_with("&lt;</u> to todo list"));
+
+        let chunks = vec![
+            "<b>bold <a href=\"https://example.com\">link".to_string(),
+            " text</a></b> done".to_string(),
+        ];
+        assert_eq!(
+            balance_html_chunks(chunks),
+            vec![
+                "<b>bold <a href=\"https://example.com\">link</a></b>",
+                "<b><a href=\"https://example.com\"> text</a></b> done",
+            ]
+        );
+    }
+
+    #[test]
+    fn saved_todos_load_back() {
+        let (mut conn, fresh) = db::open(Path::new(":memory:")).unwrap();
+        assert!(fresh);
+        let key = TodoKey::shared(ChatId(42));
+        db::save_todos(&mut conn, key, &[TodoItem::new("buy milk".to_string())]).unwrap();
+
+        let todos = db::load_todos(&conn).unwrap();
+        let tasks = &todos[&key];
+        assert_eq!(tasks.len(), 1);
+        assert_eq!(tasks[0].text, "buy milk");
+        assert!(!tasks[0].done);
+    }
+
+    #[test]
+    fn saved_alarms_load_back() {
+        let (mut conn, _) = db::open(Path::new(":memory:")).unwrap();
+        let alarm = Alarm {
+            time: NaiveTime::from_hms_opt(7, 30, 0).unwrap(),
+            text: "take meds".to_string(),
+        };
+        db::save_alarms(&mut conn, ChatId(42), &[alarm]).unwrap();
+
+        let alarms = db::load_alarms(&conn).unwrap();
+        assert_eq!(alarms[&ChatId(42)].len(), 1);
+        assert_eq!(
+            alarms[&ChatId(42)][0].time,
+            NaiveTime::from_hms_opt(7, 30, 0).unwrap()
+        );
+        assert_eq!(alarms[&ChatId(42)][0].text, "take meds");
+
+        db::save_alarms(&mut conn, ChatId(42), &[]).unwrap();
+        assert!(db::load_alarms(&conn).unwrap().is_empty());
+    }
 }